@@ -1,22 +1,40 @@
-use axum::extract::State;
+use axum::body::Body;
+use axum::extract::rejection::{JsonRejection, QueryRejection};
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Query, State};
+use axum::http::header;
+use axum::response::sse::{self, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
 use axum::{extract::Path, http::Method, Json, Router};
 use chrono::Utc;
-use deadpool_redis::Pool;
+use futures::{future, Stream, StreamExt};
 use log::debug;
 use serde_json::{json, Value};
+use server::backfill::{backfill, BackfillRequest};
 use server::catchup::catchup;
 use server::check_timeout::check_timeout;
+use server::error::{ServerError, ServerResult};
+use server::janitor::janitor;
 use server::scheduler::scheduler;
-use server::statics::{_get_default_edges, _get_default_tasks, _get_options};
+use server::statics::{
+    _get_default_edges, _get_default_tasks, _get_options, _get_scheduling_error,
+};
 use server::{
-    _get_all_task_results, _get_last_run, _get_next_run, _get_recent_runs, get_redis_pool,
+    _check_dag_exists, _get_all_task_results, _get_fire_times, _get_last_run, _get_next_run,
+    _get_recent_runs, _get_run_status, _is_run_completed, _list_runs, _validate_schedule, RunsPage,
+    ValidateScheduleRequest, DEFAULT_FIRE_TIMES_COUNT, MAX_FIRE_TIMES_COUNT,
 };
 use server::{
-    _get_all_tasks, _get_dags, _get_task, _get_task_result, _get_task_status, _trigger_run,
-    redis_runner::RedisRunner,
+    _download_run_logs, _follow_task_log, _get_all_tasks, _get_dags, _get_task, _get_task_log,
+    _get_task_log_lines, _get_task_result, _get_task_status, _search_logs, _trigger_run,
+    redact::get_redactor,
+    runner::StorageRunner,
+    storage::{get_storage, Run, RunQuery, RunStatus, Storage},
+    LogLinesQuery, LogQuery, LogSearchQuery,
 };
 use std::path::PathBuf;
 use std::str::from_utf8;
+use std::sync::Arc;
 use thepipelinetool::server::*;
 use tokio::net::TcpListener;
 use tower_http::compression::CompressionLayer;
@@ -24,7 +42,8 @@ use tower_http::cors::{Any, CorsLayer};
 use tower_http::services::ServeDir;
 use tower_http::trace::TraceLayer;
 
-use axum::routing::get;
+use axum::http::StatusCode;
+use axum::routing::{delete, get, post};
 use timed::timed;
 
 #[timed(duration(printer = "debug!"))]
@@ -32,149 +51,412 @@ async fn ping() -> &'static str {
     "pong"
 }
 
-// TODO paginate
+fn run_json(run: &Run, status: RunStatus) -> Value {
+    json!({
+        "run_id": run.run_id.to_string(),
+        "date": run.date,
+        "data_interval_start": run.data_interval_start,
+        "data_interval_end": run.data_interval_end,
+        "status": status,
+    })
+}
+
 #[timed(duration(printer = "debug!"))]
-async fn get_runs(Path(dag_name): Path<String>, State(pool): State<Pool>) -> Json<Value> {
-    json!(RedisRunner::get_runs(&dag_name, pool)
-        .await
-        .iter()
-        .map(|r| json!({
-            "run_id": r.run_id.to_string(),
-            "date": r.date,
-        }))
-        .collect::<Vec<Value>>())
-    .into()
+async fn get_runs(
+    Path(dag_name): Path<String>,
+    query: Result<Query<RunQuery>, QueryRejection>,
+    State(storage): State<Arc<dyn Storage>>,
+) -> ServerResult<Json<Value>> {
+    let Query(query) = query?;
+    let RunsPage { runs, next_cursor } = _list_runs(&dag_name, &query, storage).await?;
+
+    Ok(json!({
+        "runs": runs
+            .iter()
+            .map(|(run, status)| run_json(run, *status))
+            .collect::<Vec<Value>>(),
+        "next_cursor": next_cursor,
+    })
+    .into())
+}
+
+#[timed(duration(printer = "debug!"))]
+async fn get_next_run(Path(dag_name): Path<String>) -> ServerResult<Json<Value>> {
+    _check_dag_exists(&dag_name)?;
+    Ok(json!(_get_next_run(&dag_name)).into())
+}
+
+#[derive(Deserialize)]
+struct FireTimesQuery {
+    count: Option<usize>,
+}
+
+#[timed(duration(printer = "debug!"))]
+async fn get_fire_times(
+    Path(dag_name): Path<String>,
+    query: Result<Query<FireTimesQuery>, QueryRejection>,
+) -> ServerResult<Json<Value>> {
+    let Query(query) = query?;
+    _check_dag_exists(&dag_name)?;
+
+    let count = query
+        .count
+        .unwrap_or(DEFAULT_FIRE_TIMES_COUNT)
+        .min(MAX_FIRE_TIMES_COUNT);
+
+    Ok(json!(_get_fire_times(&dag_name, count)?).into())
 }
 
 #[timed(duration(printer = "debug!"))]
-async fn get_next_run(Path(dag_name): Path<String>) -> Json<Value> {
-    json!(_get_next_run(&dag_name)).into()
+async fn validate_schedule(
+    request: Result<Json<ValidateScheduleRequest>, JsonRejection>,
+) -> ServerResult<Json<Value>> {
+    let Json(request) = request?;
+
+    Ok(match _validate_schedule(&request) {
+        Ok(next) => json!({
+            "valid": true,
+            "next": next,
+        }),
+        Err(err) => json!({
+            "valid": false,
+            "error": err.error,
+            "position": err.position,
+        }),
+    }
+    .into())
 }
 
 #[timed(duration(printer = "debug!"))]
-async fn get_last_run(Path(dag_name): Path<String>, State(pool): State<Pool>) -> Json<Value> {
-    json!(_get_last_run(&dag_name, pool).await).into()
+async fn get_last_run(
+    Path(dag_name): Path<String>,
+    State(storage): State<Arc<dyn Storage>>,
+) -> ServerResult<Json<Value>> {
+    Ok(json!(_get_last_run(&dag_name, storage).await?).into())
 }
 
 #[timed(duration(printer = "debug!"))]
-async fn get_recent_runs(Path(dag_name): Path<String>, State(pool): State<Pool>) -> Json<Value> {
-    json!(_get_recent_runs(&dag_name, pool).await).into()
+async fn get_recent_runs(
+    Path(dag_name): Path<String>,
+    State(storage): State<Arc<dyn Storage>>,
+) -> ServerResult<Json<Value>> {
+    Ok(json!(_get_recent_runs(&dag_name, storage).await?).into())
+}
+
+async fn delete_run(
+    Path((dag_name, run_id)): Path<(String, usize)>,
+    State(storage): State<Arc<dyn Storage>>,
+) -> ServerResult<StatusCode> {
+    // the run's indexes are kept per dag, deleting it through another dag would leave them behind
+    if !storage.is_run_of_dag(&dag_name, run_id).await? {
+        return Err(ServerError::NotFound(format!(
+            "run {run_id} of {dag_name} not found"
+        )));
+    }
+    if !_is_run_completed(run_id, storage.clone()).await? {
+        return Err(ServerError::Conflict(format!(
+            "run {run_id} is still in progress"
+        )));
+    }
+
+    storage.delete_run(&dag_name, run_id).await?;
+    Ok(StatusCode::NO_CONTENT)
 }
 
 // TODO return only statuses?
 async fn get_runs_with_tasks(
     Path(dag_name): Path<String>,
-    State(pool): State<Pool>,
-) -> Json<Value> {
-    let mut res = json!({});
+    query: Result<Query<RunQuery>, QueryRejection>,
+    State(storage): State<Arc<dyn Storage>>,
+) -> ServerResult<Json<Value>> {
+    let Query(query) = query?;
+    let RunsPage { runs, next_cursor } = _list_runs(&dag_name, &query, storage.clone()).await?;
 
-    for run in RedisRunner::get_runs(&dag_name, pool.clone()).await.iter() {
+    let mut res = vec![];
+    for (run, status) in runs.iter() {
         let mut tasks = json!({});
-        for task in _get_all_tasks(run.run_id, pool.clone()) {
+        for task in _get_all_tasks(run.run_id, storage.clone()).await? {
             tasks[format!("{}_{}", task.function_name, task.id)] = json!(task);
         }
-        res[run.run_id.to_string()] = json!({
-            "date": run.date,
-            "tasks": tasks,
-        });
+        let mut run = run_json(run, *status);
+        run["tasks"] = tasks;
+        res.push(run);
     }
-    res.into()
+    Ok(json!({
+        "runs": res,
+        "next_cursor": next_cursor,
+    })
+    .into())
 }
 
-async fn get_default_tasks(Path(dag_name): Path<String>) -> Json<Value> {
-    serde_json::to_value(&_get_default_tasks(&dag_name))
-        .unwrap()
-        .into()
+async fn get_default_tasks(Path(dag_name): Path<String>) -> ServerResult<Json<Value>> {
+    _check_dag_exists(&dag_name)?;
+    Ok(json!(_get_default_tasks(&dag_name)).into())
 }
 
-async fn get_default_task(Path((dag_name, task_id)): Path<(String, usize)>) -> Json<Value> {
-    json!(&_get_default_tasks(&dag_name)
+async fn get_default_task(
+    Path((dag_name, task_id)): Path<(String, usize)>,
+) -> ServerResult<Json<Value>> {
+    _check_dag_exists(&dag_name)?;
+    match _get_default_tasks(&dag_name)
         .iter()
         .find(|t| t.id == task_id)
-        .unwrap())
-    .into()
+    {
+        Some(task) => Ok(json!(task).into()),
+        None => Err(ServerError::NotFound(format!(
+            "task {task_id} not found in {dag_name}"
+        ))),
+    }
 }
 
-async fn get_all_tasks(Path(run_id): Path<usize>, State(pool): State<Pool>) -> Json<Value> {
-    json!(_get_all_tasks(run_id, pool)).into()
+async fn get_all_tasks(
+    Path(run_id): Path<usize>,
+    State(storage): State<Arc<dyn Storage>>,
+) -> ServerResult<Json<Value>> {
+    Ok(json!(_get_all_tasks(run_id, storage).await?).into())
 }
 
 async fn get_task(
     Path((run_id, task_id)): Path<(usize, usize)>,
-    State(pool): State<Pool>,
-) -> Json<Value> {
-    json!(_get_task(run_id, task_id, pool)).into()
+    State(storage): State<Arc<dyn Storage>>,
+) -> ServerResult<Json<Value>> {
+    Ok(json!(_get_task(run_id, task_id, storage).await?).into())
 }
 
 async fn get_all_task_results(
     Path((run_id, task_id)): Path<(usize, usize)>,
-    State(pool): State<Pool>,
-) -> Json<Value> {
-    json!(_get_all_task_results(run_id, task_id, pool).await).into()
+    State(storage): State<Arc<dyn Storage>>,
+) -> ServerResult<Json<Value>> {
+    Ok(json!(_get_all_task_results(run_id, task_id, storage).await?).into())
 }
 
 async fn get_task_status(
     Path((run_id, task_id)): Path<(usize, usize)>,
-    State(pool): State<Pool>,
-) -> String {
-    from_utf8(&[_get_task_status(run_id, task_id, pool).as_u8()])
-        .unwrap()
-        .to_owned()
+    State(storage): State<Arc<dyn Storage>>,
+) -> ServerResult<String> {
+    Ok(
+        from_utf8(&[_get_task_status(run_id, task_id, storage).await?.as_u8()])
+            .unwrap()
+            .to_owned(),
+    )
 }
 
-async fn get_run_status(Path(run_id): Path<usize>, State(pool): State<Pool>) -> String {
-    // from_utf8(&[_get_task_status(run_id, task_id, pool).as_u8()])
-    //     .unwrap()
-    //     .to_owned()
-    todo!()
+async fn get_run_status(
+    Path(run_id): Path<usize>,
+    State(storage): State<Arc<dyn Storage>>,
+) -> ServerResult<String> {
+    Ok(_get_run_status(run_id, storage).await?.as_str().to_string())
 }
 
 async fn get_task_result(
     Path((run_id, task_id)): Path<(usize, usize)>,
-    State(pool): State<Pool>,
-) -> Json<Value> {
-    json!(_get_task_result(run_id, task_id, pool)).into()
+    State(storage): State<Arc<dyn Storage>>,
+) -> ServerResult<Json<Value>> {
+    Ok(json!(_get_task_result(run_id, task_id, storage).await?).into())
 }
 
 async fn get_task_log(
     Path((run_id, task_id, attempt)): Path<(usize, usize, usize)>,
-    State(pool): State<Pool>,
-) -> String {
-    RedisRunner::dummy(pool).get_log(run_id, task_id, attempt)
+    query: Result<Query<LogQuery>, QueryRejection>,
+    State(storage): State<Arc<dyn Storage>>,
+) -> ServerResult<String> {
+    let Query(query) = query?;
+    _get_task_log(run_id, task_id, attempt, query.stream, storage).await
+}
+
+async fn get_task_log_lines(
+    Path((run_id, task_id, attempt)): Path<(usize, usize, usize)>,
+    query: Result<Query<LogLinesQuery>, QueryRejection>,
+    State(storage): State<Arc<dyn Storage>>,
+) -> ServerResult<Json<Value>> {
+    let Query(query) = query?;
+    Ok(json!(_get_task_log_lines(run_id, task_id, attempt, &query, storage).await?).into())
+}
+
+async fn search_logs(
+    Path(dag_name): Path<String>,
+    query: Result<Query<LogSearchQuery>, QueryRejection>,
+    State(storage): State<Arc<dyn Storage>>,
+) -> ServerResult<Json<Value>> {
+    _check_dag_exists(&dag_name)?;
+    let Query(query) = query?;
+    Ok(json!(_search_logs(&dag_name, &query, storage).await?).into())
+}
+
+async fn download_run_logs(
+    Path(run_id): Path<usize>,
+    State(storage): State<Arc<dyn Storage>>,
+) -> ServerResult<impl IntoResponse> {
+    let chunks = _download_run_logs(run_id, storage).await?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/gzip".to_string()),
+            // already gzipped, an encoding keeps `CompressionLayer` from compressing it again
+            (header::CONTENT_ENCODING, "identity".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"run-{run_id}-logs.gz\""),
+            ),
+        ],
+        Body::from_stream(chunks),
+    ))
+}
+
+// sends the log line by line as text messages and closes once the attempt is over
+async fn follow_task_log(
+    ws: WebSocketUpgrade,
+    Path((run_id, task_id, attempt)): Path<(usize, usize, usize)>,
+    State(storage): State<Arc<dyn Storage>>,
+) -> ServerResult<Response> {
+    let mut lines = _follow_task_log(run_id, task_id, attempt, storage).await?;
+
+    Ok(ws.on_upgrade(move |mut socket: WebSocket| async move {
+        let close = loop {
+            match lines.next().await {
+                Some(Ok(batch)) => {
+                    for line in batch {
+                        // the client went away
+                        let text = serde_json::to_string(&line).unwrap();
+                        if socket.send(Message::Text(text)).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+                Some(Err(err)) => {
+                    break Some(CloseFrame {
+                        code: close_code::ERROR,
+                        reason: err.to_string().into(),
+                    })
+                }
+                None => break None,
+            }
+        };
+        let _ = socket.send(Message::Close(close)).await;
+    }))
 }
 
-async fn get_dags(State(pool): State<Pool>) -> Json<Value> {
+async fn get_dags(State(storage): State<Arc<dyn Storage>>) -> ServerResult<Json<Value>> {
     let mut result: Vec<Value> = vec![];
 
     for dag_name in _get_dags() {
         result.push(json!({
-            "last_run": _get_last_run(&dag_name, pool.clone()).await,
+            "last_run": _get_last_run(&dag_name, storage.clone()).await?,
             "next_run":_get_next_run(&dag_name),
             "options":_get_options(&dag_name),
+            "scheduling_error": _get_scheduling_error(&dag_name),
             "dag_name": &dag_name,
         }));
     }
 
-    json!(result).into()
+    Ok(json!(result).into())
 }
 
-async fn get_run_graph(Path(run_id): Path<usize>, State(pool): State<Pool>) -> Json<Value> {
-    json!(RedisRunner::dummy(pool).get_graphite_graph(run_id)).into()
+async fn get_run_graph(
+    Path(run_id): Path<usize>,
+    State(storage): State<Arc<dyn Storage>>,
+) -> ServerResult<Json<Value>> {
+    if storage.get_run(run_id).await?.is_none() {
+        return Err(ServerError::NotFound(format!("run {run_id} not found")));
+    }
+
+    Ok(json!(
+        StorageRunner::dummy(storage)
+            .blocking(move |dummy| dummy.get_graphite_graph(run_id))
+            .await?
+    )
+    .into())
 }
 
-async fn get_default_graph(Path(dag_name): Path<String>) -> Json<Value> {
+async fn get_default_graph(Path(dag_name): Path<String>) -> ServerResult<Json<Value>> {
+    _check_dag_exists(&dag_name)?;
+
     let nodes = _get_default_tasks(&dag_name);
     let edges = _get_default_edges(&dag_name);
     let mut runner = InMemoryRunner::new(&nodes, &edges);
     runner.enqueue_run("in_memory", "", Utc::now());
 
-    json!(runner.get_graphite_graph(0)).into()
+    Ok(json!(runner.get_graphite_graph(0)).into())
 }
 
-async fn trigger(Path(dag_name): Path<String>, State(pool): State<Pool>) {
+async fn trigger(
+    Path(dag_name): Path<String>,
+    State(storage): State<Arc<dyn Storage>>,
+) -> ServerResult<()> {
+    _check_dag_exists(&dag_name)?;
+
     tokio::spawn(async move {
-        _trigger_run(&dag_name, Utc::now(), pool).await;
+        if let Err(err) = _trigger_run(&dag_name, Utc::now(), storage).await {
+            println!("failed to trigger {dag_name}: {err}");
+        }
     });
+    Ok(())
+}
+
+async fn create_backfill(
+    Path(dag_name): Path<String>,
+    State(storage): State<Arc<dyn Storage>>,
+    request: Result<Json<BackfillRequest>, JsonRejection>,
+) -> ServerResult<Json<Value>> {
+    let Json(request) = request?;
+    _check_dag_exists(&dag_name)?;
+
+    Ok(json!(backfill(&dag_name, request, storage).await?).into())
+}
+
+async fn get_backfills(
+    Path(dag_name): Path<String>,
+    State(storage): State<Arc<dyn Storage>>,
+) -> ServerResult<Json<Value>> {
+    Ok(json!(storage.get_backfills(&dag_name).await?).into())
+}
+
+async fn get_backfill(
+    Path((dag_name, backfill_id)): Path<(String, usize)>,
+    State(storage): State<Arc<dyn Storage>>,
+) -> ServerResult<Json<Value>> {
+    match storage.get_backfill(backfill_id).await? {
+        Some(backfill) if backfill.dag_name == dag_name => Ok(json!(backfill).into()),
+        _ => Err(ServerError::NotFound(format!(
+            "backfill {backfill_id} of {dag_name} not found"
+        ))),
+    }
+}
+
+// server-sent events of the dag's runs, or of a single run
+async fn get_dag_events(
+    Path(dag_name): Path<String>,
+    State(storage): State<Arc<dyn Storage>>,
+) -> ServerResult<Sse<impl Stream<Item = Result<sse::Event, axum::Error>>>> {
+    stream_events(dag_name, None, storage).await
+}
+
+async fn get_run_events(
+    Path((dag_name, run_id)): Path<(String, usize)>,
+    State(storage): State<Arc<dyn Storage>>,
+) -> ServerResult<Sse<impl Stream<Item = Result<sse::Event, axum::Error>>>> {
+    stream_events(dag_name, Some(run_id), storage).await
+}
+
+async fn stream_events(
+    dag_name: String,
+    run_id: Option<usize>,
+    storage: Arc<dyn Storage>,
+) -> ServerResult<Sse<impl Stream<Item = Result<sse::Event, axum::Error>>>> {
+    _check_dag_exists(&dag_name)?;
+
+    let events = storage
+        .subscribe_events()
+        .await?
+        .filter(move |event| {
+            future::ready(
+                event.dag_name() == dag_name
+                    && (run_id.is_none() || run_id == Some(event.run_id())),
+            )
+        })
+        .map(|event| sse::Event::default().event(event.name()).json_data(&event));
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
 }
 
 #[tokio::main]
@@ -182,13 +464,15 @@ async fn main() {
     std::env::set_var("RUST_LOG", "info");
     env_logger::init();
 
-    let pool = get_redis_pool();
+    get_redactor();
+    let storage = get_storage().await;
 
     let now = Utc::now();
 
-    catchup(&now, pool.clone());
-    scheduler(&now, pool.clone());
-    check_timeout(pool.clone());
+    catchup(&now, storage.clone());
+    scheduler(&now, storage.clone());
+    check_timeout(storage.clone());
+    janitor(storage.clone());
 
     let app = Router::new()
         .nest_service("/", ServeDir::new(PathBuf::from("static")))
@@ -197,28 +481,47 @@ async fn main() {
         .route("/runs/:dag_name", get(get_runs))
         .route("/runs/next/:dag_name", get(get_next_run))
         .route("/runs/last/:dag_name", get(get_last_run))
+        .route("/schedule/:dag_name", get(get_fire_times))
+        .route("/schedule/validate", post(validate_schedule))
         .route("/runs/recent/:dag_name", get(get_recent_runs)) // TODO change to recent results?
         .route("/runs/all/:dag_name", get(get_runs_with_tasks))
+        // not under /runs/, where /runs/next/:dag_name and the like would match it
+        .route("/run/:dag_name/:run_id", delete(delete_run))
         .route("/trigger/:dag_name", get(trigger))
+        .route("/backfill/:dag_name", post(create_backfill))
+        .route("/backfills/:dag_name", get(get_backfills))
+        .route("/backfills/:dag_name/:backfill_id", get(get_backfill))
         .route("/statuses/:run_id", get(get_run_status))
         .route("/statuses/:run_id/:task_id", get(get_task_status))
         .route("/results/:run_id/:task_id", get(get_task_result))
         .route("/results/all/:run_id/:task_id", get(get_all_task_results))
         .route("/logs/:run_id/:task_id/:attempt", get(get_task_log))
+        .route(
+            "/logs/lines/:run_id/:task_id/:attempt",
+            get(get_task_log_lines),
+        )
+        .route("/logs/download/:run_id", get(download_run_logs))
+        .route("/logs/search/:dag_name", get(search_logs))
+        .route(
+            "/logs/follow/:run_id/:task_id/:attempt",
+            get(follow_task_log),
+        )
         .route("/tasks/:run_id", get(get_all_tasks))
         .route("/tasks/:run_id/:task_id", get(get_task))
         .route("/tasks/default/:dag_name", get(get_default_tasks))
         .route("/tasks/default/:dag_name/:task_id", get(get_default_task))
         .route("/graphs/:run_id", get(get_run_graph))
         .route("/graphs/default/:dag_name", get(get_default_graph))
+        .route("/events/:dag_name", get(get_dag_events))
+        .route("/events/:dag_name/:run_id", get(get_run_events))
         .layer(
             CorsLayer::new()
-                .allow_methods([Method::GET, Method::POST])
+                .allow_methods([Method::GET, Method::POST, Method::DELETE])
                 .allow_origin(Any),
         )
         .layer(TraceLayer::new_for_http())
         .layer(CompressionLayer::new())
-        .with_state(pool);
+        .with_state(storage);
 
     let listener = TcpListener::bind("0.0.0.0:8000").await.unwrap();
 
@@ -1,4 +1,10 @@
-use server::{_get_dag_path_by_name, get_redis_pool, redis_runner::RedisRunner};
+use log::error;
+use server::{
+    _get_dag_path_by_name,
+    redact::get_redactor,
+    runner::{StorageRunner, MAX_THREADS},
+    storage::get_storage,
+};
 use std::time::Duration;
 use thepipelinetool::server::*;
 use tokio::time::sleep;
@@ -8,21 +14,43 @@ async fn main() {
     std::env::set_var("RUST_LOG", "debug");
     env_logger::init();
 
-    let pool = get_redis_pool();
-    let mut dummy = RedisRunner::dummy(pool.clone());
+    get_redactor();
+    let storage = get_storage().await;
 
     loop {
-        if let Some(ordered_queued_task) = dummy.pop_priority_queue() {
-            let mut runner = RedisRunner::from_local_dag(
-                &ordered_queued_task.queued_task.dag_name,
-                pool.clone(),
-            );
-            runner.work(
-                ordered_queued_task.queued_task.run_id,
-                &ordered_queued_task,
-                _get_dag_path_by_name(&ordered_queued_task.queued_task.dag_name),
-            );
-            runner.remove_from_temp_queue(&ordered_queued_task.queued_task);
+        let ordered_queued_task = match storage.pop_priority_queue(MAX_THREADS).await {
+            Ok(ordered_queued_task) => ordered_queued_task,
+            Err(err) => {
+                error!("failed to pop queue: {err}");
+                sleep(Duration::new(2, 0)).await;
+                continue;
+            }
+        };
+
+        if let Some(ordered_queued_task) = ordered_queued_task {
+            let queued_task = ordered_queued_task.queued_task.clone();
+
+            // work runs the task process to completion, keep it off the runtime threads
+            let worked = StorageRunner::from_local_dag(&queued_task.dag_name, storage.clone())
+                .blocking(move |runner| {
+                    runner.work(
+                        ordered_queued_task.queued_task.run_id,
+                        &ordered_queued_task,
+                        _get_dag_path_by_name(&ordered_queued_task.queued_task.dag_name),
+                    )
+                })
+                .await;
+            // a task that couldn't be worked stays in the temp queue until it times out
+            if let Err(err) = worked {
+                error!(
+                    "failed to work task {} of run {}: {err}",
+                    queued_task.task_id, queued_task.run_id
+                );
+                continue;
+            }
+            if let Err(err) = storage.remove_from_temp_queue(&queued_task).await {
+                error!("failed to remove from temp queue: {err}");
+            }
         } else {
             sleep(Duration::new(2, 0)).await;
         }
@@ -0,0 +1,330 @@
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, ErrorKind, Write},
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+};
+
+use chrono::{DateTime, Utc};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use thepipelinetool::server::*;
+
+use crate::storage::{LogLine, Run, Storage, StorageError, Truncated};
+
+// one json object per line, tagged with its kind
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Record {
+    Run {
+        dag_name: String,
+        run: Run,
+    },
+    Task {
+        task: Task,
+        status: String,
+    },
+    Edge {
+        upstream: usize,
+        downstream: usize,
+    },
+    Result {
+        result: TaskResult,
+    },
+    Log {
+        task_id: usize,
+        attempt: usize,
+        #[serde(default)]
+        lines: Vec<LogLine>,
+        // archives written before lines kept their stream have the whole log as one string
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        log: String,
+        #[serde(default)]
+        truncated: bool,
+    },
+}
+
+pub struct ArchivedRun {
+    pub dag_name: String,
+    pub run: Run,
+    pub tasks: Vec<Task>,
+    pub statuses: HashMap<usize, TaskStatus>,
+    pub edges: Vec<(usize, usize)>,
+    pub results: HashMap<usize, Vec<TaskResult>>,
+    pub logs: HashMap<(usize, usize), Vec<LogLine>>,
+    // logs cut short by the size limits
+    pub truncated_logs: HashSet<(usize, usize)>,
+}
+
+impl ArchivedRun {
+    // the last attempt of the task with a log, 0 when it never ran
+    pub fn attempts(&self, task_id: usize) -> usize {
+        self.logs
+            .keys()
+            .filter(|(id, _)| *id == task_id)
+            .map(|(_, attempt)| *attempt)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+impl From<StorageError> for io::Error {
+    fn from(err: StorageError) -> Self {
+        io::Error::other(err.to_string())
+    }
+}
+
+pub fn get_archive_dir() -> String {
+    env::var("ARCHIVE_DIR")
+        .unwrap_or("./archive".to_string())
+        .to_string()
+}
+
+fn get_archive_path(run_id: usize) -> PathBuf {
+    [get_archive_dir(), format!("{run_id}.ndjson.gz")]
+        .iter()
+        .collect()
+}
+
+// `{ARCHIVE_DIR}/{dag_name}/` holds an empty `{run_id}_{logical date in ms}` file per archived
+// run of the dag, so its runs are listed without opening their archives
+fn get_index_dir(dag_name: &str) -> PathBuf {
+    [get_archive_dir(), dag_name.to_string()].iter().collect()
+}
+
+// created once the archives written before the index was kept are in it
+fn get_indexed_marker_path() -> PathBuf {
+    [get_archive_dir(), ".indexed".to_string()].iter().collect()
+}
+
+fn add_to_index(dag_name: &str, run_id: usize, date: DateTime<Utc>) -> io::Result<()> {
+    let dir = get_index_dir(dag_name);
+    fs::create_dir_all(&dir)?;
+    File::create(dir.join(format!("{run_id}_{}", date.timestamp_millis())))?;
+    Ok(())
+}
+
+// writes the run with its tasks, edges, results and logs to `{ARCHIVE_DIR}/{run_id}.ndjson.gz`
+pub async fn archive_run(
+    dag_name: &str,
+    run_id: usize,
+    storage: Arc<dyn Storage>,
+) -> io::Result<()> {
+    let Some(run) = storage.get_run(run_id).await? else {
+        return Err(io::Error::new(
+            ErrorKind::NotFound,
+            format!("run {run_id} not found"),
+        ));
+    };
+
+    let date = run.date;
+    let mut records = vec![Record::Run {
+        dag_name: dag_name.to_string(),
+        run,
+    }];
+
+    for (upstream, downstream) in storage.get_edges(run_id).await? {
+        records.push(Record::Edge {
+            upstream,
+            downstream,
+        });
+    }
+
+    for task in storage.get_all_tasks(run_id).await? {
+        let task_id = task.id;
+        let Some(status) = storage.get_task_status(run_id, task_id).await? else {
+            return Err(io::Error::new(
+                ErrorKind::NotFound,
+                format!("task {task_id} of run {run_id} has no status"),
+            ));
+        };
+        records.push(Record::Task {
+            task,
+            status: status.as_str().to_string(),
+        });
+
+        for result in storage.get_all_results(run_id, task_id).await? {
+            records.push(Record::Result { result });
+        }
+        for attempt in 1..=storage.get_attempt(run_id, task_id).await? {
+            let count = storage.get_log_line_count(run_id, task_id, attempt).await?;
+            records.push(Record::Log {
+                task_id,
+                attempt,
+                lines: storage
+                    .get_log_lines(run_id, task_id, attempt, 0, count)
+                    .await?,
+                log: String::new(),
+                truncated: storage
+                    .get_truncated(run_id, task_id, attempt)
+                    .await?
+                    .contains(&Truncated::Log),
+            });
+        }
+    }
+
+    let dag_name = dag_name.to_string();
+    tokio::task::spawn_blocking(move || {
+        write_archive(run_id, records)?;
+        add_to_index(&dag_name, run_id, date)
+    })
+    .await
+    .unwrap()
+}
+
+fn write_archive(run_id: usize, records: Vec<Record>) -> io::Result<()> {
+    fs::create_dir_all(get_archive_dir())?;
+
+    // written next to the final path and renamed so readers never see a partial archive
+    let path = get_archive_path(run_id);
+    let tmp_path = path.with_extension("tmp");
+
+    let mut encoder = GzEncoder::new(File::create(&tmp_path)?, Compression::default());
+    for record in records {
+        serde_json::to_writer(&mut encoder, &record)?;
+        encoder.write_all(b"\n")?;
+    }
+    encoder.finish()?.sync_all()?;
+
+    fs::rename(tmp_path, path)
+}
+
+// the run ids and logical dates of the archived runs of the dag
+pub async fn list_archived_runs(dag_name: &str) -> io::Result<Vec<(usize, DateTime<Utc>)>> {
+    let dag_name = dag_name.to_string();
+    tokio::task::spawn_blocking(move || {
+        index_legacy_archives()?;
+        read_index(&dag_name)
+    })
+    .await
+    .unwrap()
+}
+
+fn read_index(dag_name: &str) -> io::Result<Vec<(usize, DateTime<Utc>)>> {
+    let entries = match fs::read_dir(get_index_dir(dag_name)) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        entries => entries?,
+    };
+
+    let mut runs = vec![];
+    for entry in entries {
+        let name = entry?.file_name();
+        let Some((run_id, date)) =
+            name.to_string_lossy()
+                .split_once('_')
+                .and_then(|(run_id, date)| {
+                    Some((
+                        run_id.parse().ok()?,
+                        DateTime::from_timestamp_millis(date.parse().ok()?)?,
+                    ))
+                })
+        else {
+            continue;
+        };
+        runs.push((run_id, date));
+    }
+    Ok(runs)
+}
+
+// adds the archives written before the index was kept to it, reading their first record
+fn index_legacy_archives() -> io::Result<()> {
+    if get_indexed_marker_path().exists() {
+        return Ok(());
+    }
+    fs::create_dir_all(get_archive_dir())?;
+
+    for entry in fs::read_dir(get_archive_dir())? {
+        // archives still being written end in `.tmp`
+        let path = entry?.path();
+        if !path.to_string_lossy().ends_with(".ndjson.gz") {
+            continue;
+        }
+
+        let mut line = String::new();
+        BufReader::new(GzDecoder::new(File::open(&path)?)).read_line(&mut line)?;
+        if let Ok(Record::Run { dag_name, run }) = serde_json::from_str(&line) {
+            add_to_index(&dag_name, run.run_id, run.date)?;
+        }
+    }
+
+    File::create(get_indexed_marker_path())?;
+    Ok(())
+}
+
+// `None` when the run was never archived, a corrupt archive is an `InvalidData` error
+pub async fn read_archive(run_id: usize) -> io::Result<Option<ArchivedRun>> {
+    tokio::task::spawn_blocking(move || read_archive_file(run_id))
+        .await
+        .unwrap()
+}
+
+fn read_archive_file(run_id: usize) -> io::Result<Option<ArchivedRun>> {
+    let file = match File::open(get_archive_path(run_id)) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        file => file?,
+    };
+
+    let mut archived: Option<ArchivedRun> = None;
+    let mut tasks = vec![];
+    let mut statuses = HashMap::new();
+    let mut edges = vec![];
+    let mut results: HashMap<usize, Vec<TaskResult>> = HashMap::new();
+    let mut logs = HashMap::new();
+    let mut truncated_logs = HashSet::new();
+
+    for line in BufReader::new(GzDecoder::new(file)).lines() {
+        match serde_json::from_str(&line?)? {
+            Record::Run { dag_name, run } => {
+                archived = Some(ArchivedRun {
+                    dag_name,
+                    run,
+                    tasks: vec![],
+                    statuses: HashMap::new(),
+                    edges: vec![],
+                    results: HashMap::new(),
+                    logs: HashMap::new(),
+                    truncated_logs: HashSet::new(),
+                });
+            }
+            Record::Task { task, status } => {
+                let status = TaskStatus::from_str(&status).map_err(|_| {
+                    io::Error::new(ErrorKind::InvalidData, format!("unknown status {status}"))
+                })?;
+                statuses.insert(task.id, status);
+                tasks.push(task);
+            }
+            Record::Edge {
+                upstream,
+                downstream,
+            } => edges.push((upstream, downstream)),
+            Record::Result { result } => results.entry(result.task_id).or_default().push(result),
+            Record::Log {
+                task_id,
+                attempt,
+                mut lines,
+                log,
+                truncated,
+            } => {
+                if truncated {
+                    truncated_logs.insert((task_id, attempt));
+                }
+                if lines.is_empty() && !log.is_empty() {
+                    lines = log.lines().map(LogLine::parse).collect();
+                }
+                logs.insert((task_id, attempt), lines);
+            }
+        }
+    }
+
+    Ok(archived.map(|archived| ArchivedRun {
+        tasks,
+        statuses,
+        edges,
+        results,
+        logs,
+        truncated_logs,
+        ..archived
+    }))
+}
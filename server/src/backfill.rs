@@ -0,0 +1,201 @@
+use std::{sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use thepipelinetool::server::BlanketRunner;
+use tokio::time::sleep;
+
+use crate::{
+    _trigger_run,
+    runner::StorageRunner,
+    statics::{_get_hash, _get_options},
+    storage::{Storage, StorageResult},
+};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BackfillRequest {
+    pub start_date: DateTime<Utc>,
+    pub end_date: DateTime<Utc>,
+
+    #[serde(default)]
+    pub max_concurrent_runs: Option<usize>,
+
+    #[serde(default)]
+    pub rerun_existing: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum BackfillStatus {
+    Running,
+    Completed,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Backfill {
+    pub backfill_id: usize,
+    pub dag_name: String,
+    pub start_date: DateTime<Utc>,
+    pub end_date: DateTime<Utc>,
+    pub max_concurrent_runs: Option<usize>,
+    pub rerun_existing: bool,
+    pub status: BackfillStatus,
+    pub error: Option<String>,
+
+    // progress
+    pub total: usize,
+    pub scheduled: usize,
+    pub skipped: usize,
+    pub completed: usize,
+    pub run_ids: Vec<usize>,
+}
+
+// every slot of the dag's schedule within [start_date, end_date], capped at now
+fn get_slots(
+    dag_name: &str,
+    start_date: DateTime<Utc>,
+    end_date: DateTime<Utc>,
+) -> Result<Vec<DateTime<Utc>>, String> {
+    if start_date > end_date {
+        return Err("start_date must not be after end_date".into());
+    }
+
+    let Some(schedule) = _get_options(dag_name).get_schedule() else {
+        return Err(format!("{dag_name} has no schedule"));
+    };
+    let schedule = schedule.map_err(|err| err.error)?;
+
+    let now = Utc::now();
+    let mut slots = vec![];
+
+    for time in schedule.iter_from(start_date) {
+        if time > end_date || time > now {
+            break;
+        }
+        slots.push(time);
+    }
+
+    Ok(slots)
+}
+
+pub async fn backfill(
+    dag_name: &str,
+    request: BackfillRequest,
+    storage: Arc<dyn Storage>,
+) -> StorageResult<Backfill> {
+    let mut backfill = Backfill {
+        backfill_id: storage.get_next_backfill_id().await?,
+        dag_name: dag_name.to_owned(),
+        start_date: request.start_date,
+        end_date: request.end_date,
+        max_concurrent_runs: request.max_concurrent_runs.filter(|m| *m > 0),
+        rerun_existing: request.rerun_existing,
+        status: BackfillStatus::Running,
+        error: None,
+        total: 0,
+        scheduled: 0,
+        skipped: 0,
+        completed: 0,
+        run_ids: vec![],
+    };
+
+    let slots = match get_slots(dag_name, request.start_date, request.end_date) {
+        Ok(slots) => slots,
+        Err(err) => {
+            backfill.status = BackfillStatus::Failed;
+            backfill.error = Some(err);
+            storage.set_backfill(&backfill).await?;
+            return Ok(backfill);
+        }
+    };
+
+    backfill.total = slots.len();
+    storage.set_backfill(&backfill).await?;
+
+    let mut job = backfill.clone();
+    tokio::spawn(async move {
+        if let Err(err) = run_backfill(&mut job, slots, storage.clone()).await {
+            job.status = BackfillStatus::Failed;
+            job.error = Some(err.to_string());
+            if let Err(err) = storage.set_backfill(&job).await {
+                println!("failed to save backfill {}: {err}", job.backfill_id);
+            }
+        }
+    });
+
+    Ok(backfill)
+}
+
+async fn run_backfill(
+    job: &mut Backfill,
+    slots: Vec<DateTime<Utc>>,
+    storage: Arc<dyn Storage>,
+) -> StorageResult<()> {
+    let dag_name = job.dag_name.clone();
+    let mut in_flight: Vec<usize> = vec![];
+
+    for time in slots {
+        // same dedup as the scheduler
+        if !job.rerun_existing
+            && storage
+                .contains_logical_date(&dag_name, &_get_hash(&dag_name), time)
+                .await?
+        {
+            job.skipped += 1;
+            storage.set_backfill(job).await?;
+            continue;
+        }
+
+        if let Some(max_concurrent_runs) = job.max_concurrent_runs {
+            loop {
+                update_in_flight(job, &mut in_flight, storage.clone()).await?;
+                if in_flight.len() < max_concurrent_runs {
+                    break;
+                }
+                sleep(Duration::new(5, 0)).await;
+            }
+        }
+
+        let run_id = _trigger_run(&dag_name, time, storage.clone()).await?;
+        println!("scheduling backfill {dag_name} {}", time.format("%F %R"));
+
+        in_flight.push(run_id);
+        job.run_ids.push(run_id);
+        job.scheduled += 1;
+        storage.set_backfill(job).await?;
+    }
+
+    loop {
+        update_in_flight(job, &mut in_flight, storage.clone()).await?;
+        if in_flight.is_empty() {
+            break;
+        }
+        sleep(Duration::new(5, 0)).await;
+    }
+
+    job.status = BackfillStatus::Completed;
+    storage.set_backfill(job).await
+}
+
+async fn update_in_flight(
+    job: &mut Backfill,
+    in_flight: &mut Vec<usize>,
+    storage: Arc<dyn Storage>,
+) -> StorageResult<()> {
+    let before = in_flight.len();
+    let runs = std::mem::take(in_flight);
+
+    *in_flight = StorageRunner::dummy(storage.clone())
+        .blocking(move |dummy| {
+            runs.into_iter()
+                .filter(|run_id| !dummy.is_completed(*run_id))
+                .collect()
+        })
+        .await?;
+
+    if in_flight.len() != before {
+        job.completed = job.scheduled - in_flight.len();
+        storage.set_backfill(job).await?;
+    }
+    Ok(())
+}
@@ -1,31 +1,27 @@
+use std::sync::Arc;
+
 use chrono::{DateTime, Utc};
-use deadpool_redis::Pool;
-use saffron::Cron;
 
 use crate::{
     _get_dags, _trigger_run,
     options::DagOptions,
-    redis_runner::RedisRunner,
     statics::{_get_hash, _get_options},
+    storage::Storage,
 };
 
-pub fn catchup(up_to: &DateTime<Utc>, pool: Pool) {
+pub fn catchup(up_to: &DateTime<Utc>, storage: Arc<dyn Storage>) {
     let up_to: DateTime<Utc> = *up_to;
     tokio::spawn(async move {
         let dags = _get_dags();
 
         for dag_name in dags {
-            let pool = pool.clone();
+            let storage = storage.clone();
 
             tokio::spawn(async move {
                 let options: DagOptions = _get_options(&dag_name);
-                if let Some(schedule) = &options.schedule {
-                    match schedule.parse::<Cron>() {
-                        Ok(cron) => {
-                            if !cron.any() {
-                                println!("Cron will never match any given time!");
-                                return;
-                            }
+                if let Some(schedule) = options.get_schedule() {
+                    match schedule {
+                        Ok(schedule) => {
                             println!("checking for catchup: {dag_name}");
 
                             if let Some(start_date) = options.start_date {
@@ -34,8 +30,8 @@ pub fn catchup(up_to: &DateTime<Utc>, pool: Pool) {
                                 }
                             }
 
-                            let futures = cron.clone().iter_from(
-                                if let Some(start_date) = options.start_date {
+                            let futures =
+                                schedule.iter_from(if let Some(start_date) = options.start_date {
                                     if options.catchup {
                                         start_date.into()
                                     } else {
@@ -43,15 +39,10 @@ pub fn catchup(up_to: &DateTime<Utc>, pool: Pool) {
                                     }
                                 } else {
                                     up_to
-                                },
-                            );
+                                });
 
                             // remove take 10
                             'inner: for time in futures {
-                                if !cron.contains(time) {
-                                    println!("Failed check! Cron does not contain {}.", time);
-                                    break 'inner;
-                                }
                                 if time >= up_to {
                                     break 'inner;
                                 }
@@ -61,22 +52,28 @@ pub fn catchup(up_to: &DateTime<Utc>, pool: Pool) {
                                     }
                                 }
                                 // check if date is already in db
-                                if RedisRunner::contains_logical_date(
-                                    &dag_name,
-                                    &_get_hash(&dag_name),
-                                    time,
-                                    pool.clone(),
-                                )
-                                .await
+                                match storage
+                                    .contains_logical_date(&dag_name, &_get_hash(&dag_name), time)
+                                    .await
                                 {
-                                    continue 'inner;
+                                    Ok(true) => continue 'inner,
+                                    Ok(false) => {}
+                                    Err(err) => {
+                                        println!("failed catchup {dag_name}: {err}");
+                                        return;
+                                    }
                                 }
 
-                                _trigger_run(&dag_name, time, pool.clone()).await;
+                                if let Err(err) =
+                                    _trigger_run(&dag_name, time, storage.clone()).await
+                                {
+                                    println!("failed catchup {dag_name}: {err}");
+                                    return;
+                                }
                                 println!("scheduling catchup {dag_name} {}", time.format("%F %R"));
                             }
                         }
-                        Err(err) => println!("{err}: {schedule}"),
+                        Err(err) => println!("{}", err.error),
                     }
                 }
             });
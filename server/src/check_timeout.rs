@@ -1,36 +1,20 @@
-use std::time::Duration;
+use std::{sync::Arc, time::Duration};
 
 use chrono::{DateTime, FixedOffset, Utc};
 
-use deadpool_redis::Pool;
-use thepipelinetool::server::{BlanketRunner, Runner, TaskResult};
+use thepipelinetool::server::{BlanketRunner, TaskResult};
 use tokio::time::sleep;
 
-use crate::redis_runner::RedisRunner;
+use crate::{
+    runner::StorageRunner,
+    storage::{Storage, StorageResult},
+};
 
-pub fn check_timeout(pool: Pool) {
+pub fn check_timeout(storage: Arc<dyn Storage>) {
     tokio::spawn(async move {
-        let mut dummy = RedisRunner::dummy(pool);
         loop {
-            for queued_task in dummy.get_temp_queue().await {
-                let task = dummy.get_task_by_id(queued_task.run_id, queued_task.task_id);
-                if let Some(timeout) = task.options.timeout {
-                    let now: DateTime<FixedOffset> = Utc::now().into();
-                    if (now - queued_task.queued_date).to_std().unwrap() > timeout {
-                        let result = TaskResult::premature_error(
-                            task.id,
-                            queued_task.attempt,
-                            task.options.max_attempts,
-                            task.function_name.clone(),
-                            "timed out".to_string(),
-                            task.is_branch,
-                        );
-
-                        dummy.handle_task_result(queued_task.run_id, result, &queued_task);
-                    }
-                } else {
-                    continue;
-                }
+            if let Err(err) = fail_timed_out_tasks(storage.clone()).await {
+                println!("failed to check timeouts: {err}");
             }
 
             // TODO read from env
@@ -38,3 +22,36 @@ pub fn check_timeout(pool: Pool) {
         }
     });
 }
+
+async fn fail_timed_out_tasks(storage: Arc<dyn Storage>) -> StorageResult<()> {
+    for queued_task in storage.get_temp_queue().await? {
+        let Some(task) = storage
+            .get_task_by_id(queued_task.run_id, queued_task.task_id)
+            .await?
+        else {
+            continue;
+        };
+        if let Some(timeout) = task.options.timeout {
+            let now: DateTime<FixedOffset> = Utc::now().into();
+            if (now - queued_task.queued_date).to_std().unwrap() > timeout {
+                let result = TaskResult::premature_error(
+                    task.id,
+                    queued_task.attempt,
+                    task.options.max_attempts,
+                    task.function_name.clone(),
+                    "timed out".to_string(),
+                    task.is_branch,
+                );
+
+                StorageRunner::dummy(storage.clone())
+                    .blocking(move |dummy| {
+                        dummy.handle_task_result(queued_task.run_id, result, &queued_task)
+                    })
+                    .await?;
+            }
+        } else {
+            continue;
+        }
+    }
+    Ok(())
+}
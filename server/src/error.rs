@@ -0,0 +1,85 @@
+use std::fmt;
+
+use axum::{
+    extract::rejection::{JsonRejection, QueryRejection},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+
+use crate::{schedule::ScheduleError, storage::StorageError};
+
+// what a handler can fail with, rendered as the matching status and `{"error": "..."}`
+#[derive(Debug)]
+pub enum ServerError {
+    // unknown dag, run, task or backfill
+    NotFound(String),
+    // malformed body or query
+    BadRequest(String),
+    // the request is valid but the current state doesn't allow it
+    Conflict(String),
+    // storage could not be reached or failed the request
+    Unavailable(String),
+}
+
+pub type ServerResult<T> = Result<T, ServerError>;
+
+impl ServerError {
+    fn status(&self) -> StatusCode {
+        match self {
+            ServerError::NotFound(_) => StatusCode::NOT_FOUND,
+            ServerError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ServerError::Conflict(_) => StatusCode::CONFLICT,
+            ServerError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            ServerError::NotFound(message)
+            | ServerError::BadRequest(message)
+            | ServerError::Conflict(message)
+            | ServerError::Unavailable(message) => message,
+        }
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.status(), self.message())
+    }
+}
+
+impl IntoResponse for ServerError {
+    fn into_response(self) -> Response {
+        (self.status(), Json(json!({ "error": self.message() }))).into_response()
+    }
+}
+
+impl From<StorageError> for ServerError {
+    fn from(err: StorageError) -> Self {
+        ServerError::Unavailable(err.to_string())
+    }
+}
+
+impl From<ScheduleError> for ServerError {
+    fn from(err: ScheduleError) -> Self {
+        ServerError::BadRequest(match err.position {
+            Some(position) => format!("invalid schedule: {} at {position}", err.error),
+            None => format!("invalid schedule: {}", err.error),
+        })
+    }
+}
+
+impl From<JsonRejection> for ServerError {
+    fn from(rejection: JsonRejection) -> Self {
+        ServerError::BadRequest(rejection.body_text())
+    }
+}
+
+impl From<QueryRejection> for ServerError {
+    fn from(rejection: QueryRejection) -> Self {
+        ServerError::BadRequest(rejection.body_text())
+    }
+}
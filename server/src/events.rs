@@ -0,0 +1,72 @@
+use chrono::{DateTime, Utc};
+use futures::stream::BoxStream;
+use thepipelinetool::server::*;
+
+use crate::storage::{RunStatus, StorageResult, Truncated};
+
+// state transitions published by the storage backends as they happen
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    RunCreated {
+        dag_name: String,
+        run_id: usize,
+        logical_date: DateTime<Utc>,
+    },
+    TaskStatus {
+        dag_name: String,
+        run_id: usize,
+        task_id: usize,
+        status: String,
+        run_status: RunStatus,
+    },
+    TaskResult {
+        dag_name: String,
+        run_id: usize,
+        task_id: usize,
+        attempt: usize,
+        success: bool,
+        // last stderr lines of a failed attempt
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        stderr_tail: Vec<String>,
+        // parts of the attempt cut short by the size limits
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        truncated: Vec<Truncated>,
+    },
+}
+
+pub type EventStream = BoxStream<'static, Event>;
+
+// events are published after the change they report is stored, so failing to publish one
+// must not fail the change
+pub fn log_publish_failure(result: StorageResult<()>) {
+    if let Err(err) = result {
+        println!("failed to publish event: {err}");
+    }
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::RunCreated { .. } => "run_created",
+            Event::TaskStatus { .. } => "task_status",
+            Event::TaskResult { .. } => "task_result",
+        }
+    }
+
+    pub fn dag_name(&self) -> &str {
+        match self {
+            Event::RunCreated { dag_name, .. }
+            | Event::TaskStatus { dag_name, .. }
+            | Event::TaskResult { dag_name, .. } => dag_name,
+        }
+    }
+
+    pub fn run_id(&self) -> usize {
+        match self {
+            Event::RunCreated { run_id, .. }
+            | Event::TaskStatus { run_id, .. }
+            | Event::TaskResult { run_id, .. } => *run_id,
+        }
+    }
+}
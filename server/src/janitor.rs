@@ -0,0 +1,58 @@
+use std::{sync::Arc, time::Duration};
+
+use chrono::Utc;
+use tokio::time::sleep;
+
+use crate::{
+    _get_dags, _is_run_completed,
+    archive::archive_run,
+    statics::_get_options,
+    storage::{Storage, StorageResult},
+};
+
+pub fn janitor(storage: Arc<dyn Storage>) {
+    tokio::spawn(async move {
+        loop {
+            for dag_name in _get_dags() {
+                if let Err(err) = purge_expired_runs(&dag_name, storage.clone()).await {
+                    println!("failed to purge runs of {dag_name}: {err}");
+                }
+            }
+
+            // TODO read from env
+            sleep(Duration::new(60, 0)).await;
+        }
+    });
+}
+
+async fn purge_expired_runs(dag_name: &str, storage: Arc<dyn Storage>) -> StorageResult<()> {
+    let options = _get_options(dag_name);
+    if options.max_runs_kept.is_none() && options.retain_for.is_none() {
+        return Ok(());
+    }
+
+    // a `retain_for` reaching past the earliest date leaves nothing old enough
+    let older_than = options.retain_for.and_then(|retain_for| {
+        Utc::now().checked_sub_signed(chrono::Duration::from_std(retain_for).ok()?)
+    });
+    let run_ids = storage
+        .get_expired_run_ids(dag_name, older_than, options.max_runs_kept)
+        .await?;
+
+    for run_id in run_ids {
+        // runs still in flight are picked up again once they complete
+        if !_is_run_completed(run_id, storage.clone()).await? {
+            continue;
+        }
+
+        if let Err(err) = archive_run(dag_name, run_id, storage.clone()).await {
+            println!("failed to archive run {run_id} of {dag_name}: {err}");
+            continue;
+        }
+
+        storage.delete_run(dag_name, run_id).await?;
+        println!("archived and purged run {run_id} of {dag_name}");
+    }
+
+    Ok(())
+}
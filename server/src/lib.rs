@@ -1,22 +1,70 @@
-use std::{env, fs, io::ErrorKind, path::PathBuf};
+use std::{
+    cmp::Reverse,
+    collections::HashSet,
+    env, fs,
+    io::{self, ErrorKind, Write},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 
+use archive::{list_archived_runs, read_archive, ArchivedRun};
 use chrono::{DateTime, Utc};
-use deadpool::Runtime;
-use deadpool_redis::{Config, Pool};
+use error::{ServerError, ServerResult};
+use flate2::{write::GzEncoder, Compression};
+use futures::{
+    channel::mpsc,
+    future,
+    stream::{self, BoxStream},
+    SinkExt, StreamExt,
+};
 use log::{debug, info};
-use redis_runner::{RedisRunner, Run};
-use saffron::Cron;
+use options::DagOptions;
+use regex::{Regex, RegexBuilder};
+use runner::StorageRunner;
+use schedule::{get_next_fire_times, get_previous_fire_times, Schedule, ScheduleError};
+use serde::{Deserialize, Serialize};
+use storage::{
+    is_unfinished, LogLine, LogStream, Run, RunQuery, RunStatus, SortOrder, Storage, StorageResult,
+    Truncated,
+};
 use thepipelinetool::server::*;
 use timed::timed;
 
 use crate::statics::{_get_hash, _get_options};
 
+pub mod archive;
+pub mod backfill;
 pub mod catchup;
 pub mod check_timeout;
+pub mod error;
+pub mod events;
+pub mod janitor;
+pub mod limits;
 pub mod options;
-pub mod redis_runner;
+pub mod redact;
+pub mod redis_pool;
+pub mod redis_storage;
+pub mod runner;
+pub mod schedule;
 pub mod scheduler;
+pub mod sql_storage;
 pub mod statics;
+pub mod storage;
+
+pub const DEFAULT_FIRE_TIMES_COUNT: usize = 5;
+pub const MAX_FIRE_TIMES_COUNT: usize = 100;
+pub const DEFAULT_RUNS_LIMIT: usize = 50;
+pub const MAX_RUNS_LIMIT: usize = 500;
+pub const LOG_FOLLOW_BATCH: usize = 1000;
+pub const DEFAULT_LOG_LINES_LIMIT: usize = 1000;
+pub const MAX_LOG_LINES_LIMIT: usize = 10_000;
+pub const DEFAULT_LOG_SEARCH_LIMIT: usize = 100;
+pub const MAX_LOG_SEARCH_LIMIT: usize = 1000;
+// a single search looks at no more runs, log lines and compiled pattern bytes than these
+pub const LOG_SEARCH_MAX_RUNS: usize = 20;
+pub const LOG_SEARCH_MAX_LINES: usize = 100_000;
+pub const LOG_SEARCH_MAX_PATTERN_SIZE: usize = 1 << 20;
 
 pub fn get_dags_dir() -> String {
     env::var("DAGS_DIR")
@@ -24,40 +72,683 @@ pub fn get_dags_dir() -> String {
         .to_string()
 }
 
-fn get_redis_url() -> String {
+pub fn get_redis_url() -> String {
     env::var("REDIS_URL")
         .unwrap_or("redis://0.0.0.0:6379".to_string())
         .to_string()
 }
 
+// namespaces every redis key, e.g. `staging` gives `staging:queue`
+pub fn get_redis_key_prefix() -> String {
+    env::var("REDIS_KEY_PREFIX")
+        .unwrap_or("".to_string())
+        .to_string()
+}
+
 pub fn _get_dag_path_by_name(dag_name: &str) -> PathBuf {
     let dags_dir = &get_dags_dir();
     [dags_dir, dag_name].iter().collect()
 }
 
+// `None` while the run is still in storage, runs that are in neither are not found
+async fn _get_archived_run(
+    run_id: usize,
+    storage: Arc<dyn Storage>,
+) -> ServerResult<Option<ArchivedRun>> {
+    if storage.get_run(run_id).await?.is_some() {
+        return Ok(None);
+    }
+    match read_archive(run_id)
+        .await
+        .map_err(|err| archive_error(run_id, err))?
+    {
+        Some(archived) => Ok(Some(archived)),
+        None => Err(ServerError::NotFound(format!("run {run_id} not found"))),
+    }
+}
+
+fn archive_error(run_id: usize, err: io::Error) -> ServerError {
+    ServerError::Unavailable(format!("could not read the archive of run {run_id}: {err}"))
+}
+
+fn task_not_found(run_id: usize, task_id: usize) -> ServerError {
+    ServerError::NotFound(format!("task {task_id} not found in run {run_id}"))
+}
+
 #[timed(duration(printer = "debug!"))]
-pub fn _get_all_tasks(run_id: usize, pool: Pool) -> Vec<Task> {
-    RedisRunner::dummy(pool).get_all_tasks(run_id)
+pub async fn _get_all_tasks(run_id: usize, storage: Arc<dyn Storage>) -> ServerResult<Vec<Task>> {
+    if let Some(archived) = _get_archived_run(run_id, storage.clone()).await? {
+        return Ok(archived.tasks);
+    }
+    Ok(storage.get_all_tasks(run_id).await?)
+}
+
+#[timed(duration(printer = "debug!"))]
+pub async fn _get_task(
+    run_id: usize,
+    task_id: usize,
+    storage: Arc<dyn Storage>,
+) -> ServerResult<Task> {
+    let task = match _get_archived_run(run_id, storage.clone()).await? {
+        Some(archived) => archived.tasks.into_iter().find(|task| task.id == task_id),
+        None => storage.get_task_by_id(run_id, task_id).await?,
+    };
+    task.ok_or_else(|| task_not_found(run_id, task_id))
+}
+
+#[timed(duration(printer = "debug!"))]
+pub async fn _get_all_task_results(
+    run_id: usize,
+    task_id: usize,
+    storage: Arc<dyn Storage>,
+) -> ServerResult<Vec<TaskResult>> {
+    if let Some(mut archived) = _get_archived_run(run_id, storage.clone()).await? {
+        return Ok(archived.results.remove(&task_id).unwrap_or_default());
+    }
+    Ok(storage.get_all_results(run_id, task_id).await?)
 }
 
 #[timed(duration(printer = "debug!"))]
-pub fn _get_task(run_id: usize, task_id: usize, pool: Pool) -> Task {
-    RedisRunner::dummy(pool).get_task_by_id(run_id, task_id)
+pub async fn _get_task_status(
+    run_id: usize,
+    task_id: usize,
+    storage: Arc<dyn Storage>,
+) -> ServerResult<TaskStatus> {
+    let status = match _get_archived_run(run_id, storage.clone()).await? {
+        Some(mut archived) => archived.statuses.remove(&task_id),
+        None => storage.get_task_status(run_id, task_id).await?,
+    };
+    status.ok_or_else(|| task_not_found(run_id, task_id))
 }
 
 #[timed(duration(printer = "debug!"))]
-pub async fn _get_all_task_results(run_id: usize, task_id: usize, pool: Pool) -> Vec<TaskResult> {
-    RedisRunner::get_all_results(run_id, task_id, pool).await
+pub async fn _get_run_status(run_id: usize, storage: Arc<dyn Storage>) -> ServerResult<RunStatus> {
+    if let Some(archived) = _get_archived_run(run_id, storage.clone()).await? {
+        let statuses = archived.statuses.values();
+        return Ok(RunStatus::from_counts(
+            statuses.clone().filter(|s| is_unfinished(s)).count(),
+            statuses
+                .filter(|s| matches!(s, TaskStatus::Failure))
+                .count(),
+        ));
+    }
+    storage
+        .get_run_status(run_id)
+        .await?
+        .ok_or_else(|| ServerError::NotFound(format!("run {run_id} not found")))
 }
 
 #[timed(duration(printer = "debug!"))]
-pub fn _get_task_status(run_id: usize, task_id: usize, pool: Pool) -> TaskStatus {
-    RedisRunner::dummy(pool).get_task_status(run_id, task_id)
+pub async fn _get_task_result(
+    run_id: usize,
+    task_id: usize,
+    storage: Arc<dyn Storage>,
+) -> ServerResult<TaskResult> {
+    let result = match _get_archived_run(run_id, storage.clone()).await? {
+        Some(mut archived) => archived
+            .results
+            .remove(&task_id)
+            .and_then(|mut results| results.pop()),
+        None => storage.get_task_result(run_id, task_id).await?,
+    };
+    result.ok_or_else(|| {
+        ServerError::NotFound(format!("task {task_id} in run {run_id} has no result yet"))
+    })
 }
 
 #[timed(duration(printer = "debug!"))]
-pub fn _get_task_result(run_id: usize, task_id: usize, pool: Pool) -> TaskResult {
-    RedisRunner::dummy(pool).get_task_result(run_id, task_id)
+pub async fn _get_task_log(
+    run_id: usize,
+    task_id: usize,
+    attempt: usize,
+    stream: Option<LogStream>,
+    storage: Arc<dyn Storage>,
+) -> ServerResult<String> {
+    let lines = match _get_archived_run(run_id, storage.clone()).await? {
+        Some(mut archived) => archived
+            .logs
+            .remove(&(task_id, attempt))
+            .unwrap_or_default(),
+        None if stream.is_none() => return Ok(storage.get_log(run_id, task_id, attempt).await?),
+        None => {
+            let count = storage.get_log_line_count(run_id, task_id, attempt).await?;
+            storage
+                .get_log_lines(run_id, task_id, attempt, 0, count)
+                .await?
+        }
+    };
+    Ok(lines
+        .into_iter()
+        .filter(|line| stream.is_none() || stream == Some(line.stream))
+        .map(|line| line.line)
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+#[derive(Deserialize, Default)]
+pub struct LogQuery {
+    #[serde(default)]
+    pub stream: Option<LogStream>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct LogLinesQuery {
+    #[serde(default)]
+    pub offset: Option<usize>,
+
+    #[serde(default)]
+    pub limit: Option<usize>,
+
+    // the last `tail` lines, takes precedence over `offset` and `limit`
+    #[serde(default)]
+    pub tail: Option<usize>,
+
+    // offset, limit and tail select lines of the whole log, stream then filters them
+    #[serde(default)]
+    pub stream: Option<LogStream>,
+}
+
+#[derive(Serialize)]
+pub struct LogLines {
+    pub lines: Vec<LogLine>,
+    // line number of the first line
+    pub offset: usize,
+    pub total: usize,
+    // the attempt's log was cut short by the size limits
+    pub truncated: bool,
+}
+
+// the offset and limit of the lines the query asks for out of `total`
+fn log_window(query: &LogLinesQuery, total: usize) -> (usize, usize) {
+    match query.tail {
+        Some(tail) => {
+            let tail = tail.min(MAX_LOG_LINES_LIMIT);
+            (total.saturating_sub(tail), tail)
+        }
+        None => (
+            query.offset.unwrap_or(0),
+            query
+                .limit
+                .unwrap_or(DEFAULT_LOG_LINES_LIMIT)
+                .min(MAX_LOG_LINES_LIMIT),
+        ),
+    }
+}
+
+#[timed(duration(printer = "debug!"))]
+pub async fn _get_task_log_lines(
+    run_id: usize,
+    task_id: usize,
+    attempt: usize,
+    query: &LogLinesQuery,
+    storage: Arc<dyn Storage>,
+) -> ServerResult<LogLines> {
+    let (lines, offset, total, truncated) = match _get_archived_run(run_id, storage.clone()).await?
+    {
+        Some(mut archived) => {
+            let log = archived
+                .logs
+                .remove(&(task_id, attempt))
+                .unwrap_or_default();
+            let total = log.len();
+            let (offset, limit) = log_window(query, total);
+            (
+                log.into_iter().skip(offset).take(limit).collect(),
+                offset,
+                total,
+                archived.truncated_logs.contains(&(task_id, attempt)),
+            )
+        }
+        None => {
+            let total = storage.get_log_line_count(run_id, task_id, attempt).await?;
+            let (offset, limit) = log_window(query, total);
+            (
+                storage
+                    .get_log_lines(run_id, task_id, attempt, offset, limit)
+                    .await?,
+                offset,
+                total,
+                storage
+                    .get_truncated(run_id, task_id, attempt)
+                    .await?
+                    .contains(&Truncated::Log),
+            )
+        }
+    };
+
+    Ok(LogLines {
+        lines: lines
+            .into_iter()
+            .filter(|line: &LogLine| query.stream.is_none() || query.stream == Some(line.stream))
+            .collect(),
+        offset,
+        total,
+        truncated,
+    })
+}
+
+// every log of the run as one gzipped text, compressed while it's sent, each log starts with a
+// `==> {function_name}_{task_id} attempt {attempt} <==` header line
+pub async fn _download_run_logs(
+    run_id: usize,
+    storage: Arc<dyn Storage>,
+) -> ServerResult<BoxStream<'static, io::Result<Vec<u8>>>> {
+    let archived = _get_archived_run(run_id, storage.clone()).await?;
+
+    let (mut tx, rx) = mpsc::channel(4);
+    tokio::spawn(async move {
+        if let Err(err) = write_run_logs(run_id, archived, storage, &mut tx).await {
+            let _ = tx.send(Err(err)).await;
+        }
+    });
+    Ok(rx.boxed())
+}
+
+async fn write_run_logs(
+    run_id: usize,
+    archived: Option<ArchivedRun>,
+    storage: Arc<dyn Storage>,
+    tx: &mut mpsc::Sender<io::Result<Vec<u8>>>,
+) -> io::Result<()> {
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    let tasks = match &archived {
+        Some(archived) => archived.tasks.clone(),
+        None => storage.get_all_tasks(run_id).await?,
+    };
+
+    for task in tasks {
+        let attempts = match &archived {
+            Some(archived) => archived.attempts(task.id),
+            None => storage.get_attempt(run_id, task.id).await?,
+        };
+
+        for attempt in 1..=attempts {
+            writeln!(
+                encoder,
+                "==> {}_{} attempt {attempt} <==",
+                task.function_name, task.id
+            )?;
+
+            match &archived {
+                Some(archived) => {
+                    for line in archived.logs.get(&(task.id, attempt)).into_iter().flatten() {
+                        writeln!(encoder, "{}", line.line)?;
+                    }
+                }
+                None => {
+                    let mut offset = 0;
+                    loop {
+                        let lines = storage
+                            .get_log_lines(run_id, task.id, attempt, offset, LOG_FOLLOW_BATCH)
+                            .await?;
+                        if lines.is_empty() {
+                            break;
+                        }
+                        offset += lines.len();
+                        for line in lines {
+                            writeln!(encoder, "{}", line.line)?;
+                        }
+                    }
+                }
+            }
+
+            // hands over what's been compressed so far, the client went away if it can't
+            let chunk = std::mem::take(encoder.get_mut());
+            if !chunk.is_empty() && tx.send(Ok(chunk)).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+
+    let _ = tx.send(Ok(encoder.finish()?)).await;
+    Ok(())
+}
+
+// an attempt is over once its task finished or a later attempt was enqueued, a task that is
+// gone won't write any more either
+async fn is_attempt_over(
+    run_id: usize,
+    task_id: usize,
+    attempt: usize,
+    storage: Arc<dyn Storage>,
+) -> StorageResult<bool> {
+    let Some(status) = storage.get_task_status(run_id, task_id).await? else {
+        return Ok(true);
+    };
+    Ok(!is_unfinished(&status) || storage.get_attempt(run_id, task_id).await? > attempt)
+}
+
+// batches of log lines, the existing ones first and then new ones as they're appended, until
+// the attempt is over
+pub async fn _follow_task_log(
+    run_id: usize,
+    task_id: usize,
+    attempt: usize,
+    storage: Arc<dyn Storage>,
+) -> ServerResult<BoxStream<'static, StorageResult<Vec<LogLine>>>> {
+    if let Some(mut archived) = _get_archived_run(run_id, storage.clone()).await? {
+        let lines = archived
+            .logs
+            .remove(&(task_id, attempt))
+            .unwrap_or_default();
+        return Ok(stream::once(future::ready(Ok(lines))).boxed());
+    }
+
+    Ok(stream::unfold(Some(0), move |offset| {
+        let storage = storage.clone();
+        async move {
+            let mut offset = offset?;
+            loop {
+                // checked before reading so the last lines of the attempt are still sent
+                let over = match is_attempt_over(run_id, task_id, attempt, storage.clone()).await {
+                    Ok(over) => over,
+                    Err(err) => return Some((Err(err), None)),
+                };
+                let lines = match storage
+                    .get_log_lines(run_id, task_id, attempt, offset, LOG_FOLLOW_BATCH)
+                    .await
+                {
+                    Ok(lines) => lines,
+                    Err(err) => return Some((Err(err), None)),
+                };
+
+                if !lines.is_empty() {
+                    offset += lines.len();
+                    return Some((Ok(lines), Some(offset)));
+                }
+                if over {
+                    return None;
+                }
+                // TODO read from env
+                tokio::time::sleep(Duration::from_millis(500)).await;
+            }
+        }
+    })
+    .boxed())
+}
+
+#[derive(Deserialize, Default)]
+pub struct LogSearchQuery {
+    // substring to look for, or a pattern when `regex` is set
+    #[serde(default)]
+    pub q: String,
+
+    #[serde(default)]
+    pub regex: bool,
+
+    #[serde(default)]
+    pub ignore_case: bool,
+
+    #[serde(default)]
+    pub task_id: Option<usize>,
+
+    #[serde(default)]
+    pub function_name: Option<String>,
+
+    #[serde(default)]
+    pub stream: Option<LogStream>,
+
+    // inclusive bounds on the logical date of the runs
+    #[serde(default)]
+    pub from: Option<DateTime<Utc>>,
+
+    #[serde(default)]
+    pub to: Option<DateTime<Utc>>,
+
+    // run id of the last run searched by the previous page
+    #[serde(default)]
+    pub cursor: Option<usize>,
+
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct LogMatch {
+    pub run_id: usize,
+    pub task_id: usize,
+    pub function_name: String,
+    pub attempt: usize,
+    pub line_no: usize,
+    #[serde(flatten)]
+    pub line: LogLine,
+}
+
+#[derive(Serialize)]
+pub struct LogSearchResults {
+    pub matches: Vec<LogMatch>,
+    // set when more runs are left to search
+    pub next_cursor: Option<usize>,
+    // a run was cut short by the limits, the rest of its logs were not searched
+    pub truncated: bool,
+}
+
+fn log_search_limit(query: &LogSearchQuery) -> usize {
+    query
+        .limit
+        .unwrap_or(DEFAULT_LOG_SEARCH_LIMIT)
+        .clamp(1, MAX_LOG_SEARCH_LIMIT)
+}
+
+fn log_search_pattern(query: &LogSearchQuery) -> ServerResult<Regex> {
+    if query.q.is_empty() {
+        return Err(ServerError::BadRequest("q must not be empty".to_string()));
+    }
+
+    let pattern = match query.regex {
+        true => query.q.clone(),
+        false => regex::escape(&query.q),
+    };
+    RegexBuilder::new(&pattern)
+        .case_insensitive(query.ignore_case)
+        .size_limit(LOG_SEARCH_MAX_PATTERN_SIZE)
+        .build()
+        .map_err(|err| ServerError::BadRequest(err.to_string()))
+}
+
+// appends the matching lines of the run's logs, read from its archive once it's purged,
+// false when the limits cut it short
+async fn search_run_logs(
+    run_id: usize,
+    archived: Option<&ArchivedRun>,
+    query: &LogSearchQuery,
+    pattern: &Regex,
+    matches: &mut Vec<LogMatch>,
+    scanned: &mut usize,
+    storage: Arc<dyn Storage>,
+) -> StorageResult<bool> {
+    let limit = log_search_limit(query);
+    let tasks = match archived {
+        Some(archived) => archived.tasks.clone(),
+        None => storage.get_all_tasks(run_id).await?,
+    };
+    let mut tasks: Vec<Task> = tasks
+        .into_iter()
+        .filter(|task| query.task_id.is_none() || query.task_id == Some(task.id))
+        .filter(|task| {
+            query.function_name.is_none()
+                || query.function_name.as_ref() == Some(&task.function_name)
+        })
+        .collect();
+    tasks.sort_by_key(|task| task.id);
+
+    for task in tasks {
+        let attempts = match archived {
+            Some(archived) => archived.attempts(task.id),
+            None => storage.get_attempt(run_id, task.id).await?,
+        };
+
+        for attempt in 1..=attempts {
+            let mut offset = 0;
+            loop {
+                if *scanned >= LOG_SEARCH_MAX_LINES {
+                    return Ok(false);
+                }
+                let count = LOG_FOLLOW_BATCH.min(LOG_SEARCH_MAX_LINES - *scanned);
+                let lines = match archived {
+                    Some(archived) => archived
+                        .logs
+                        .get(&(task.id, attempt))
+                        .into_iter()
+                        .flatten()
+                        .skip(offset)
+                        .take(count)
+                        .cloned()
+                        .collect(),
+                    None => {
+                        storage
+                            .get_log_lines(run_id, task.id, attempt, offset, count)
+                            .await?
+                    }
+                };
+                if lines.is_empty() {
+                    break;
+                }
+                let count = lines.len();
+                *scanned += count;
+
+                for (i, line) in lines.into_iter().enumerate() {
+                    if (query.stream.is_some() && query.stream != Some(line.stream))
+                        || !pattern.is_match(&line.line)
+                    {
+                        continue;
+                    }
+                    if matches.len() >= limit {
+                        return Ok(false);
+                    }
+                    matches.push(LogMatch {
+                        run_id,
+                        task_id: task.id,
+                        function_name: task.function_name.clone(),
+                        attempt,
+                        line_no: offset + i,
+                        line,
+                    });
+                }
+                offset += count;
+            }
+        }
+    }
+    Ok(true)
+}
+
+// newest runs first, a page ends after `LOG_SEARCH_MAX_RUNS` runs, `limit` matches or
+// `LOG_SEARCH_MAX_LINES` scanned lines
+#[timed(duration(printer = "debug!"))]
+pub async fn _search_logs(
+    dag_name: &str,
+    query: &LogSearchQuery,
+    storage: Arc<dyn Storage>,
+) -> ServerResult<LogSearchResults> {
+    if let (Some(from), Some(to)) = (query.from, query.to) {
+        if from > to {
+            return Err(ServerError::BadRequest(
+                "from must not be after to".to_string(),
+            ));
+        }
+    }
+    let pattern = log_search_pattern(query)?;
+
+    let run_query = RunQuery {
+        from: query.from,
+        to: query.to,
+        cursor: query.cursor,
+        order: SortOrder::Desc,
+        ..Default::default()
+    };
+    let run_query = _with_cursor_date(dag_name, &run_query, storage.clone()).await?;
+    let mut runs: Vec<(usize, DateTime<Utc>, bool)> = storage
+        .list_runs(dag_name, &run_query, LOG_SEARCH_MAX_RUNS)
+        .await?
+        .into_iter()
+        .map(|(run, _)| (run.run_id, run.date, false))
+        .collect();
+
+    // purged runs are only left in the archive, they are paged with the stored ones in the
+    // order of the listing, newest first by logical date within a date range
+    let by_date = query.from.is_some() || query.to.is_some();
+    let key =
+        |run_id: usize, date: DateTime<Utc>| (by_date.then_some(Reverse(date)), Reverse(run_id));
+    let after = run_query
+        .cursor
+        .map(|cursor| key(cursor, run_query.cursor_date.unwrap_or_default()));
+    // a run archived but not purged yet is searched in storage
+    let stored: HashSet<usize> = runs.iter().map(|(run_id, _, _)| *run_id).collect();
+    let archived_runs = list_archived_runs(dag_name)
+        .await
+        .map_err(|err| ServerError::Unavailable(format!("could not list archived runs: {err}")))?;
+    runs.extend(
+        archived_runs
+            .into_iter()
+            .filter(|(run_id, _)| !stored.contains(run_id))
+            .filter(|(run_id, date)| after.is_none_or(|after| key(*run_id, *date) > after))
+            .filter(|(_, date)| query.from.is_none_or(|from| *date >= from))
+            .filter(|(_, date)| query.to.is_none_or(|to| *date <= to))
+            .map(|(run_id, date)| (run_id, date, true)),
+    );
+    runs.sort_by_key(|(run_id, date, _)| key(*run_id, *date));
+    runs.truncate(LOG_SEARCH_MAX_RUNS);
+
+    let mut matches = vec![];
+    let mut scanned = 0;
+    for (i, &(run_id, _, archived)) in runs.iter().enumerate() {
+        let archived = match archived {
+            true => read_archive(run_id)
+                .await
+                .map_err(|err| archive_error(run_id, err))?,
+            false => None,
+        };
+        let run_start = matches.len();
+        let done = search_run_logs(
+            run_id,
+            archived.as_ref(),
+            query,
+            &pattern,
+            &mut matches,
+            &mut scanned,
+            storage.clone(),
+        )
+        .await?;
+        if done {
+            continue;
+        }
+
+        // the next page searches the run again from its start, unless it's the first run of
+        // this one which would never get through then
+        if i > 0 {
+            matches.truncate(run_start);
+            return Ok(LogSearchResults {
+                matches,
+                next_cursor: Some(runs[i - 1].0),
+                truncated: false,
+            });
+        }
+        return Ok(LogSearchResults {
+            matches,
+            next_cursor: Some(run_id),
+            truncated: true,
+        });
+    }
+
+    // a full page may be followed by more runs
+    let next_cursor = runs
+        .last()
+        .filter(|_| runs.len() == LOG_SEARCH_MAX_RUNS)
+        .map(|(run_id, _, _)| *run_id);
+    Ok(LogSearchResults {
+        matches,
+        next_cursor,
+        truncated: false,
+    })
+}
+
+// dags are the executables in `DAGS_DIR`, anything else is not found
+pub fn _check_dag_exists(dag_name: &str) -> ServerResult<()> {
+    if _get_dags().iter().any(|name| name == dag_name) {
+        Ok(())
+    } else {
+        Err(ServerError::NotFound(format!("dag {dag_name} not found")))
+    }
 }
 
 // TODO cache response to prevent disk read
@@ -90,16 +781,24 @@ pub fn _get_dags() -> Vec<String> {
 }
 
 #[timed(duration(printer = "debug!"))]
-pub async fn _trigger_run(dag_name: &str, logical_date: DateTime<Utc>, pool: Pool) {
-    let hash = _get_hash(dag_name);
-
-    RedisRunner::from_local_dag(dag_name, pool.clone()).enqueue_run(dag_name, &hash, logical_date);
+pub async fn _is_run_completed(run_id: usize, storage: Arc<dyn Storage>) -> StorageResult<bool> {
+    StorageRunner::dummy(storage)
+        .blocking(move |dummy| dummy.is_completed(run_id))
+        .await
 }
 
-// #[timed(duration(printer = "debug!"))]
-pub fn get_redis_pool() -> Pool {
-    let cfg = Config::from_url(get_redis_url());
-    cfg.create_pool(Some(Runtime::Tokio1)).unwrap()
+#[timed(duration(printer = "debug!"))]
+pub async fn _trigger_run(
+    dag_name: &str,
+    logical_date: DateTime<Utc>,
+    storage: Arc<dyn Storage>,
+) -> StorageResult<usize> {
+    let hash = _get_hash(dag_name);
+    let dag_name = dag_name.to_string();
+
+    StorageRunner::from_local_dag(&dag_name, storage)
+        .blocking(move |runner| runner.enqueue_new_run(&dag_name, &hash, logical_date))
+        .await
 }
 
 // #[macro_export]
@@ -123,72 +822,189 @@ pub fn get_redis_pool() -> Pool {
 //     };
 // }
 
+fn get_next_run_from(options: &DagOptions) -> DateTime<Utc> {
+    if let Some(start_date) = options.start_date {
+        if options.catchup || start_date > Utc::now() {
+            return start_date.into();
+        }
+    }
+    Utc::now()
+}
+
 pub fn _get_next_run(dag_name: &str) -> Vec<Value> {
-    let options = _get_options(&dag_name);
+    match _get_fire_times(dag_name, 1) {
+        Ok(fire_times) => fire_times
+            .next
+            .iter()
+            .map(|time| {
+                json!({
+                    "date": format!("{}", time.format("%F %R"))
+                })
+            })
+            .collect(),
+        Err(err) => {
+            info!("{}", err.error);
+            vec![]
+        }
+    }
+}
 
-    // info!("{:#?}", options);
+#[derive(Serialize, Deserialize)]
+pub struct FireTimes {
+    pub next: Vec<DateTime<Utc>>,
+    pub previous: Vec<DateTime<Utc>>,
+}
 
-    if let Some(schedule) = &options.schedule {
-        match schedule.parse::<Cron>() {
-            Ok(cron) => {
-                if !cron.any() {
-                    info!("Cron will never match any given time!");
-                    return vec![];
-                }
+pub fn _get_fire_times(dag_name: &str, count: usize) -> Result<FireTimes, ScheduleError> {
+    let options = _get_options(dag_name);
 
-                if let Some(start_date) = options.start_date {
-                    info!("Start date: {start_date}");
-                } else {
-                    info!("Start date: None");
-                }
+    let Some(schedule) = options.get_schedule() else {
+        return Ok(FireTimes {
+            next: vec![],
+            previous: vec![],
+        });
+    };
+    let schedule = schedule?;
 
-                info!("Upcoming:");
-                let futures =
-                    cron.clone()
-                        .iter_from(if let Some(start_date) = options.start_date {
-                            if options.catchup || start_date > Utc::now() {
-                                start_date.into()
-                            } else {
-                                Utc::now()
-                            }
-                        } else {
-                            Utc::now()
-                        });
-                let mut next_runs = vec![];
-                for time in futures.take(1) {
-                    if !cron.contains(time) {
-                        info!("Failed check! Cron does not contain {}.", time);
-                        break;
-                    }
-                    if let Some(end_date) = options.end_date {
-                        if time > end_date {
-                            break;
-                        }
-                    }
-                    next_runs.push(json!({
-                        "date": format!("{}", time.format("%F %R"))
-                    }));
-                    info!("  {}", time.format("%F %R"));
-                }
+    let start_date = options.start_date.map(|d| d.into());
+    let end_date = options.end_date.map(|d| d.into());
+    let now = Utc::now();
 
-                return next_runs;
-            }
-            Err(err) => info!("{err}: {schedule}"),
+    Ok(FireTimes {
+        next: get_next_fire_times(&schedule, get_next_run_from(&options), end_date, count),
+        previous: get_previous_fire_times(
+            &schedule,
+            end_date.map_or(now, |end_date: DateTime<Utc>| end_date.min(now)),
+            start_date,
+            count,
+        ),
+    })
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ValidateScheduleRequest {
+    pub schedule: String,
+
+    #[serde(default)]
+    pub start_date: Option<DateTime<Utc>>,
+
+    #[serde(default)]
+    pub end_date: Option<DateTime<Utc>>,
+
+    #[serde(default)]
+    pub count: Option<usize>,
+}
+
+pub fn _validate_schedule(
+    request: &ValidateScheduleRequest,
+) -> Result<Vec<DateTime<Utc>>, ScheduleError> {
+    if let (Some(start_date), Some(end_date)) = (request.start_date, request.end_date) {
+        if start_date > end_date {
+            return Err(ScheduleError {
+                error: "start_date must not be after end_date".into(),
+                position: None,
+            });
         }
     }
 
-    vec![]
+    let schedule = Schedule::parse(&request.schedule, request.start_date)?;
+    let from = match request.start_date {
+        Some(start_date) if start_date > Utc::now() => start_date,
+        _ => Utc::now(),
+    };
+
+    Ok(get_next_fire_times(
+        &schedule,
+        from,
+        request.end_date,
+        request
+            .count
+            .unwrap_or(DEFAULT_FIRE_TIMES_COUNT)
+            .min(MAX_FIRE_TIMES_COUNT),
+    ))
 }
 
-pub async fn _get_last_run(dag_name: &str, pool: Pool) -> Vec<Run> {
-    let r = RedisRunner::get_last_run(&dag_name, pool).await;
+pub async fn _get_last_run(dag_name: &str, storage: Arc<dyn Storage>) -> StorageResult<Vec<Run>> {
+    let r = storage.get_last_run(dag_name).await?;
 
-    match r {
+    Ok(match r {
         Some(run) => vec![run],
         None => vec![],
+    })
+}
+
+// the query with the logical date of its cursor's run when it has a date range, the run may
+// only be left in the archive by now
+async fn _with_cursor_date(
+    dag_name: &str,
+    query: &RunQuery,
+    storage: Arc<dyn Storage>,
+) -> ServerResult<RunQuery> {
+    let mut query = query.clone();
+    let Some(cursor) = query
+        .cursor
+        .filter(|_| query.from.is_some() || query.to.is_some())
+    else {
+        return Ok(query);
+    };
+
+    query.cursor_date = match storage.get_run(cursor).await? {
+        Some(run) => Some(run.date),
+        None => list_archived_runs(dag_name)
+            .await
+            .map_err(|err| {
+                ServerError::Unavailable(format!("could not list archived runs: {err}"))
+            })?
+            .into_iter()
+            .find_map(|(run_id, date)| (run_id == cursor).then_some(date)),
+    };
+    if query.cursor_date.is_none() {
+        return Err(ServerError::BadRequest(format!(
+            "cursor {cursor} not found"
+        )));
     }
+    Ok(query)
+}
+
+pub struct RunsPage {
+    pub runs: Vec<(Run, RunStatus)>,
+    // pass as `cursor` to get the next page, `None` on the last page
+    pub next_cursor: Option<usize>,
+}
+
+#[timed(duration(printer = "debug!"))]
+pub async fn _list_runs(
+    dag_name: &str,
+    query: &RunQuery,
+    storage: Arc<dyn Storage>,
+) -> ServerResult<RunsPage> {
+    if let (Some(from), Some(to)) = (query.from, query.to) {
+        if from > to {
+            return Err(ServerError::BadRequest(
+                "from must not be after to".to_string(),
+            ));
+        }
+    }
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_RUNS_LIMIT)
+        .clamp(1, MAX_RUNS_LIMIT);
+    let query = &_with_cursor_date(dag_name, query, storage.clone()).await?;
+    // one more run than the page holds tells whether there is a next one
+    let mut runs = storage.list_runs(dag_name, query, limit + 1).await?;
+    let next_cursor = if runs.len() > limit {
+        runs.truncate(limit);
+        runs.last().map(|(run, _)| run.run_id)
+    } else {
+        None
+    };
+    Ok(RunsPage { runs, next_cursor })
 }
 
-pub async fn _get_recent_runs(dag_name: &str, pool: Pool) -> Vec<Run> {
-    RedisRunner::get_recent_runs(&dag_name, pool).await
+pub async fn _get_recent_runs(
+    dag_name: &str,
+    storage: Arc<dyn Storage>,
+) -> StorageResult<Vec<Run>> {
+    storage.get_recent_runs(dag_name).await
 }
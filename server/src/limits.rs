@@ -0,0 +1,73 @@
+use std::env;
+
+use thepipelinetool::server::*;
+
+// bytes of log a single attempt may write, stdout and stderr together
+pub fn get_max_log_bytes() -> usize {
+    env::var("MAX_LOG_BYTES")
+        .ok()
+        .and_then(|max| max.parse().ok())
+        .unwrap_or(10 * 1024 * 1024)
+}
+
+// bytes of the result, stdout and stderr a task result may hold each
+pub fn get_max_result_bytes() -> usize {
+    env::var("MAX_RESULT_BYTES")
+        .ok()
+        .and_then(|max| max.parse().ok())
+        .unwrap_or(1024 * 1024)
+}
+
+pub fn log_truncated_marker(max: usize) -> String {
+    format!("[log truncated at {max} bytes]")
+}
+
+fn truncated_marker(len: usize, max: usize) -> String {
+    format!("[truncated {len} bytes to {max}]")
+}
+
+// the longest prefix of `s` of at most `max` bytes
+pub fn prefix(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s;
+    }
+    let mut end = max;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+// `s` cut to `max` bytes followed by the marker, `None` when it fits
+fn truncate(s: &str, max: usize) -> Option<String> {
+    (s.len() > max).then(|| format!("{}{}", prefix(s, max), truncated_marker(s.len(), max)))
+}
+
+// the result with its value, stdout and stderr cut to `max` bytes each, `None` when it fits,
+// a value that is too big is replaced by the start of its json as a string
+pub fn truncate_result(result: &TaskResult, max: usize) -> Option<TaskResult> {
+    let value = serde_json::to_string(&result.result).unwrap();
+    let value = truncate(&value, max);
+    let stdout = truncate(&result.stdout, max);
+    let stderr = truncate(&result.stderr, max);
+    if value.is_none() && stdout.is_none() && stderr.is_none() {
+        return None;
+    }
+
+    let mut result = result.clone();
+    if let Some(value) = value {
+        result.result = Value::String(value);
+    }
+    if let Some(stdout) = stdout {
+        result.stdout = stdout;
+    }
+    if let Some(stderr) = stderr {
+        result.stderr = stderr;
+    }
+    Some(result)
+}
+
+// a stdout line that is too big to become the attempt's output is cut like a result
+pub fn truncate_output(line: &str, max: usize) -> String {
+    truncate(line, max).unwrap_or(line.to_string())
+}
@@ -1,8 +1,10 @@
 use std::time::Duration;
 
-use chrono::{DateTime, FixedOffset};
+use chrono::{DateTime, FixedOffset, Utc};
 use serde::{Deserialize, Serialize};
 
+use crate::schedule::{get_previous_fire_times, Schedule, ScheduleError};
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct DagOptions {
     #[serde(default)]
@@ -25,6 +27,13 @@ pub struct DagOptions {
 
     #[serde(default)]
     pub catchup: bool,
+
+    // completed runs beyond the newest `max_runs_kept` or older than `retain_for` get purged
+    #[serde(default)]
+    pub max_runs_kept: Option<usize>,
+
+    #[serde(default)]
+    pub retain_for: Option<Duration>,
 }
 
 impl Default for DagOptions {
@@ -37,6 +46,32 @@ impl Default for DagOptions {
             retry_delay: Duration::ZERO,
             timeout: None,
             catchup: false,
+            max_runs_kept: None,
+            retain_for: None,
         }
     }
 }
+
+impl DagOptions {
+    pub fn get_schedule(&self) -> Option<Result<Schedule, ScheduleError>> {
+        self.schedule
+            .as_ref()
+            .map(|schedule| Schedule::parse(schedule, self.start_date.map(|d| d.into())))
+    }
+
+    // the window a run is responsible for: from the previous slot up to its logical date
+    pub fn get_data_interval(&self, logical_date: DateTime<Utc>) -> (DateTime<Utc>, DateTime<Utc>) {
+        let previous = match self.get_schedule() {
+            Some(Ok(schedule)) => get_previous_fire_times(
+                &schedule,
+                logical_date,
+                self.start_date.map(|d| d.into()),
+                1,
+            )
+            .pop(),
+            _ => None,
+        };
+
+        (previous.unwrap_or(logical_date), logical_date)
+    }
+}
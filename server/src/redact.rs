@@ -0,0 +1,113 @@
+use std::{env, fs, sync::OnceLock};
+
+use regex::Regex;
+use thepipelinetool::server::*;
+
+pub const REDACTED: &str = "[REDACTED]";
+
+static REDACTOR: OnceLock<Redactor> = OnceLock::new();
+
+// names of env vars holding secrets, comma separated, e.g. `DATABASE_URL,API_TOKEN`
+fn get_redact_secret_env() -> Vec<String> {
+    env::var("REDACT_SECRET_ENV")
+        .unwrap_or("".to_string())
+        .split(',')
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+// a file with one secret value per line
+fn get_redact_secrets_file() -> Option<String> {
+    env::var("REDACT_SECRETS_FILE").ok()
+}
+
+// a file with one regex per line, e.g. `postgres://[^\s]+`
+fn get_redact_patterns_file() -> Option<String> {
+    env::var("REDACT_PATTERNS_FILE").ok()
+}
+
+fn read_lines(path: &str) -> Vec<String> {
+    fs::read_to_string(path)
+        .unwrap_or_else(|err| panic!("could not read {path}: {err}"))
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.to_string())
+        .collect()
+}
+
+// replaces known secret values and matches of the configured patterns with `[REDACTED]`
+pub struct Redactor {
+    // `None` when nothing is configured
+    regex: Option<Regex>,
+}
+
+impl Redactor {
+    pub fn new(secrets: &[String], patterns: &[String]) -> Result<Self, regex::Error> {
+        // longest secrets first so one that contains another is redacted whole
+        let mut secrets: Vec<&String> = secrets.iter().filter(|s| !s.is_empty()).collect();
+        secrets.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+
+        let alternatives: Vec<String> = secrets
+            .into_iter()
+            .map(|secret| regex::escape(secret))
+            .chain(patterns.iter().map(|pattern| format!("(?:{pattern})")))
+            .collect();
+        if alternatives.is_empty() {
+            return Ok(Self { regex: None });
+        }
+        Ok(Self {
+            regex: Some(Regex::new(&alternatives.join("|"))?),
+        })
+    }
+
+    // `REDACT_SECRET_ENV`, `REDACT_SECRETS_FILE` and `REDACT_PATTERNS_FILE`
+    fn from_env() -> Self {
+        let mut secrets: Vec<String> = get_redact_secret_env()
+            .iter()
+            .filter_map(|name| env::var(name).ok())
+            .collect();
+        if let Some(path) = get_redact_secrets_file() {
+            secrets.extend(read_lines(&path));
+        }
+        let patterns = get_redact_patterns_file()
+            .map(|path| read_lines(&path))
+            .unwrap_or_default();
+
+        Self::new(&secrets, &patterns).unwrap_or_else(|err| panic!("invalid redact pattern: {err}"))
+    }
+
+    pub fn redact(&self, s: &str) -> String {
+        match &self.regex {
+            Some(regex) => regex.replace_all(s, REDACTED).into_owned(),
+            None => s.to_string(),
+        }
+    }
+
+    pub fn redact_value(&self, value: &mut Value) {
+        match value {
+            Value::String(s) => *s = self.redact(s),
+            Value::Array(values) => values.iter_mut().for_each(|v| self.redact_value(v)),
+            Value::Object(map) => map.values_mut().for_each(|v| self.redact_value(v)),
+            _ => {}
+        }
+    }
+
+    pub fn redact_result(&self, result: &TaskResult) -> TaskResult {
+        let mut result = result.clone();
+        if self.regex.is_none() {
+            return result;
+        }
+
+        self.redact_value(&mut result.result);
+        result.stdout = self.redact(&result.stdout);
+        result.stderr = self.redact(&result.stderr);
+        result.premature_failure_error_str = self.redact(&result.premature_failure_error_str);
+        result
+    }
+}
+
+// loaded from the env on first use, call it at startup so bad configuration fails early
+pub fn get_redactor() -> &'static Redactor {
+    REDACTOR.get_or_init(Redactor::from_env)
+}
@@ -0,0 +1,206 @@
+use std::env;
+
+use async_trait::async_trait;
+use deadpool::{
+    managed::{self, Metrics, RecycleError, RecycleResult},
+    Runtime,
+};
+use deadpool_redis::{
+    redis::{
+        aio::{ConnectionLike, MultiplexedConnection, PubSub},
+        cluster::ClusterClient,
+        cluster_async::ClusterConnection,
+        cmd,
+        sentinel::{Sentinel, SentinelClient, SentinelServerType},
+        Client, Cmd, ErrorKind, Pipeline, RedisError, RedisFuture, RedisResult, Value,
+    },
+    Config,
+};
+use tokio::sync::{Mutex, OnceCell};
+
+use crate::get_redis_url;
+
+fn get_redis_mode() -> String {
+    env::var("REDIS_MODE")
+        .unwrap_or("standalone".to_string())
+        .to_string()
+}
+
+// comma separated, e.g. `redis://sentinel-1:26379,redis://sentinel-2:26379`
+fn get_redis_sentinel_urls() -> Vec<String> {
+    env::var("REDIS_SENTINEL_URLS")
+        .unwrap_or("redis://0.0.0.0:26379".to_string())
+        .split(',')
+        .map(|url| url.trim().to_string())
+        .collect()
+}
+
+fn get_redis_sentinel_master() -> String {
+    env::var("REDIS_SENTINEL_MASTER")
+        .unwrap_or("mymaster".to_string())
+        .to_string()
+}
+
+// comma separated seed nodes, defaults to `REDIS_URL`
+fn get_redis_cluster_urls() -> Vec<String> {
+    env::var("REDIS_CLUSTER_URLS")
+        .unwrap_or(get_redis_url())
+        .split(',')
+        .map(|url| url.trim().to_string())
+        .collect()
+}
+
+// asks the sentinels for the current master whenever a connection is created
+pub struct SentinelManager {
+    client: Mutex<SentinelClient>,
+}
+
+#[async_trait]
+impl managed::Manager for SentinelManager {
+    type Type = MultiplexedConnection;
+    type Error = RedisError;
+
+    async fn create(&self) -> Result<MultiplexedConnection, RedisError> {
+        self.client.lock().await.get_async_connection().await
+    }
+
+    // a master demoted by a failover only accepts reads, dropping its connections makes the
+    // next `create` resolve the new master
+    async fn recycle(
+        &self,
+        conn: &mut MultiplexedConnection,
+        _: &Metrics,
+    ) -> RecycleResult<RedisError> {
+        let role: Vec<Value> = cmd("ROLE").query_async(conn).await?;
+        match role.first() {
+            Some(Value::Data(role)) if role == b"master" => Ok(()),
+            _ => Err(RecycleError::StaticMessage("no longer the master")),
+        }
+    }
+}
+
+pub enum RedisPool {
+    Standalone(deadpool_redis::Pool),
+    Sentinel(managed::Pool<SentinelManager>),
+    // cluster connections are multiplexed and route each command to its slot's node,
+    // a single shared one is enough
+    Cluster {
+        client: ClusterClient,
+        conn: OnceCell<ClusterConnection>,
+    },
+}
+
+pub enum RedisConnection {
+    Standalone(deadpool_redis::Connection),
+    Sentinel(managed::Object<SentinelManager>),
+    Cluster(ClusterConnection),
+}
+
+// `REDIS_MODE=standalone` (default) uses `REDIS_URL`,
+// `REDIS_MODE=sentinel` uses `REDIS_SENTINEL_URLS` and `REDIS_SENTINEL_MASTER`,
+// `REDIS_MODE=cluster` uses `REDIS_CLUSTER_URLS`
+pub fn get_redis_pool() -> RedisPool {
+    match get_redis_mode().as_str() {
+        "standalone" => {
+            let cfg = Config::from_url(get_redis_url());
+            RedisPool::Standalone(cfg.create_pool(Some(Runtime::Tokio1)).unwrap())
+        }
+        "sentinel" => {
+            let client = SentinelClient::build(
+                get_redis_sentinel_urls(),
+                get_redis_sentinel_master(),
+                None,
+                SentinelServerType::Master,
+            )
+            .unwrap();
+            RedisPool::Sentinel(
+                managed::Pool::builder(SentinelManager {
+                    client: Mutex::new(client),
+                })
+                .runtime(Runtime::Tokio1)
+                .build()
+                .unwrap(),
+            )
+        }
+        "cluster" => RedisPool::Cluster {
+            client: ClusterClient::new(get_redis_cluster_urls()).unwrap(),
+            conn: OnceCell::new(),
+        },
+        mode => panic!("unknown REDIS_MODE: {mode}"),
+    }
+}
+
+impl RedisPool {
+    pub fn is_cluster(&self) -> bool {
+        matches!(self, RedisPool::Cluster { .. })
+    }
+
+    pub async fn get(&self) -> RedisResult<RedisConnection> {
+        match self {
+            RedisPool::Standalone(pool) => pool
+                .get()
+                .await
+                .map(RedisConnection::Standalone)
+                .map_err(pool_error),
+            RedisPool::Sentinel(pool) => pool
+                .get()
+                .await
+                .map(RedisConnection::Sentinel)
+                .map_err(pool_error),
+            RedisPool::Cluster { client, conn } => conn
+                .get_or_try_init(|| client.get_async_connection())
+                .await
+                .map(|conn| RedisConnection::Cluster(conn.clone())),
+        }
+    }
+
+    // subscribers need a dedicated connection, on a cluster published messages reach every
+    // node so the first one will do
+    pub async fn get_pubsub(&self) -> RedisResult<PubSub> {
+        let client = match self {
+            RedisPool::Standalone(_) => Client::open(get_redis_url())?,
+            RedisPool::Sentinel(_) => {
+                Sentinel::build(get_redis_sentinel_urls())?
+                    .async_master_for(&get_redis_sentinel_master(), None)
+                    .await?
+            }
+            RedisPool::Cluster { .. } => Client::open(get_redis_cluster_urls()[0].as_str())?,
+        };
+        Ok(client.get_async_connection().await?.into_pubsub())
+    }
+}
+
+fn pool_error(err: impl ToString) -> RedisError {
+    RedisError::from((ErrorKind::IoError, "pool error", err.to_string()))
+}
+
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        match self {
+            RedisConnection::Standalone(conn) => conn.req_packed_command(cmd),
+            RedisConnection::Sentinel(conn) => conn.req_packed_command(cmd),
+            RedisConnection::Cluster(conn) => conn.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        match self {
+            RedisConnection::Standalone(conn) => conn.req_packed_commands(cmd, offset, count),
+            RedisConnection::Sentinel(conn) => conn.req_packed_commands(cmd, offset, count),
+            RedisConnection::Cluster(conn) => conn.req_packed_commands(cmd, offset, count),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            RedisConnection::Standalone(conn) => conn.get_db(),
+            RedisConnection::Sentinel(conn) => conn.get_db(),
+            RedisConnection::Cluster(conn) => conn.get_db(),
+        }
+    }
+}
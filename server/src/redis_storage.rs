@@ -0,0 +1,1583 @@
+use async_trait::async_trait;
+use deadpool_redis::redis::{cmd, pipe, Pipeline, Script};
+use futures::{future, StreamExt};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    ops::Range,
+};
+
+use chrono::{DateTime, Utc};
+use std::str::FromStr;
+use thepipelinetool::server::*;
+
+use crate::{
+    backfill::Backfill,
+    events::{log_publish_failure, Event, EventStream},
+    redis_pool::{RedisConnection, RedisPool},
+    storage::{
+        is_unfinished, unfinished_statuses, LogLine, LogStream, Run, RunQuery, RunStatus,
+        SortOrder, Storage, StorageResult, Truncated, STDERR_TAIL_LINES, STDERR_TAIL_SCAN,
+    },
+};
+
+pub struct RedisStorage {
+    pool: RedisPool,
+    key_prefix: String,
+}
+
+const QUEUE_KEY: &str = "queue";
+const TEMP_QUEUE_KEY: &str = "tmpqueue";
+
+const TASK_STATUS_KEY: &str = "ts";
+const RUN_KEY: &str = "r";
+const TASK_RESULTS_KEY: &str = "trs";
+const RUNS_KEY: &str = "runs";
+const LOGICAL_DATES_KEY: &str = "ld";
+const DEPTH_KEY: &str = "d";
+const TASK_RESULT_KEY: &str = "tr";
+const LOG_KEY: &str = "l";
+// the last line of each attempt, see `Storage::get_last_output`
+const OUTPUT_KEY: &str = "o";
+// the parts of each attempt cut short by the size limits
+const TRUNCATED_KEY: &str = "tc";
+const TASK_ATTEMPT_KEY: &str = "a";
+const DEPENDENCY_KEYS_KEY: &str = "dk";
+const EDGES_KEY: &str = "e";
+const UPSTREAM_KEY: &str = "up";
+const DOWNSTREAM_KEY: &str = "dn";
+const EDGES_INDEXED_KEY: &str = "ei";
+const TASKS_KEY: &str = "tasks";
+const TASK_ID_KEY: &str = "ti";
+// tasks used to be kept as a set of serialized tasks, a key per task and a key per template args
+const LEGACY_TASKS_KEY: &str = "tks";
+const LEGACY_TASK_KEY: &str = "t";
+const LEGACY_TEMPLATE_ARGS_KEY: &str = "ta";
+// set once a run's tasks are in `tasks`, runs created since the change start out with it
+const TASKS_MIGRATED_KEY: &str = "tm";
+const BACKFILL_ID_KEY: &str = "backfill";
+const BACKFILL_KEY: &str = "bf";
+const BACKFILLS_KEY: &str = "bfs";
+// per dag indexes of its runs, `ri` and `rsi:{status}` are scored by run id and `rd` by logical
+// date, `rh` maps run ids to runs
+const RUN_INDEX_KEY: &str = "ri";
+const RUN_STATUS_INDEX_KEY: &str = "rsi";
+const RUN_DATES_KEY: &str = "rd";
+const RUN_HASH_KEY: &str = "rh";
+const RUNS_INDEXED_KEY: &str = "rix";
+// dag of a run and its number of unfinished and failed tasks
+const RUN_COUNTS_KEY: &str = "rc";
+const EVENTS_CHANNEL: &str = "ev";
+
+// runs of `rd` a page of a date range reads at a time
+const RUN_DATES_BATCH: usize = 100;
+
+// moves the lowest scored task to the temp queue unless `ARGV[1]` tasks are already running
+const POP_PRIORITY_QUEUE_SCRIPT: &str = r"
+if redis.call('SCARD', KEYS[2]) >= tonumber(ARGV[1]) then
+    return {}
+end
+local popped = redis.call('ZPOPMIN', KEYS[1], 1)
+if popped[1] then
+    redis.call('SADD', KEYS[2], popped[1])
+end
+return popped
+";
+
+// sets a task status and moves the run's task counts along, `ARGV[2]` is the failed status and
+// `ARGV[3..]` the unfinished ones, returns the run's dag and counts
+const SET_TASK_STATUS_SCRIPT: &str = r"
+local old = redis.call('GET', KEYS[1])
+redis.call('SET', KEYS[1], ARGV[1])
+local function unfinished(status)
+    for i = 3, #ARGV do
+        if status == ARGV[i] then
+            return 1
+        end
+    end
+    return 0
+end
+local function failed(status)
+    if status == ARGV[2] then
+        return 1
+    end
+    return 0
+end
+-- runs created before the counts existed have no dag in their counts
+if old and redis.call('HEXISTS', KEYS[2], 'dag') == 1 then
+    redis.call('HINCRBY', KEYS[2], 'unfinished', unfinished(ARGV[1]) - unfinished(old))
+    redis.call('HINCRBY', KEYS[2], 'failed', failed(ARGV[1]) - failed(old))
+end
+return redis.call('HMGET', KEYS[2], 'dag', 'unfinished', 'failed')
+";
+
+impl RedisStorage {
+    pub fn new(pool: RedisPool, key_prefix: String) -> Self {
+        Self { pool, key_prefix }
+    }
+
+    // every key goes through here so deployments sharing one redis don't see each other's state
+    fn key(&self, key: impl Display) -> String {
+        if self.key_prefix.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}:{key}", self.key_prefix)
+        }
+    }
+
+    // on a cluster, keys sharing a hash tag live in the same slot, which multi-key commands,
+    // transactions and scripts require
+    fn tag(&self, id: impl Display) -> String {
+        if self.pool.is_cluster() {
+            format!("{{{id}}}")
+        } else {
+            id.to_string()
+        }
+    }
+
+    fn run_key(&self, key: &str, run_id: usize) -> String {
+        self.key(format!("{key}:{}", self.tag(run_id)))
+    }
+
+    fn task_key(&self, key: &str, run_id: usize, task_id: impl Display) -> String {
+        format!("{}:{task_id}", self.run_key(key, run_id))
+    }
+
+    fn dag_key(&self, key: &str, dag_name: &str) -> String {
+        self.key(format!("{key}:{}", self.tag(dag_name)))
+    }
+
+    fn queue_key(&self, key: &str) -> String {
+        if self.pool.is_cluster() {
+            self.key(format!("{}:{key}", self.tag(QUEUE_KEY)))
+        } else {
+            self.key(key)
+        }
+    }
+
+    // returns the members of an `up`/`dn` adjacency set, runs created before the index existed
+    // only have the `e:{run_id}` edge set so their index is built on first access
+    async fn get_adjacent(
+        &self,
+        run_id: usize,
+        key: &str,
+        task_id: usize,
+    ) -> StorageResult<Vec<usize>> {
+        let mut conn = self.pool.get().await?;
+        let (indexed, adjacent): (bool, Vec<usize>) = pipe()
+            .cmd("EXISTS")
+            .arg(self.run_key(EDGES_INDEXED_KEY, run_id))
+            .cmd("SMEMBERS")
+            .arg(self.task_key(key, run_id, task_id))
+            .query_async(&mut conn)
+            .await?;
+
+        if indexed {
+            return Ok(adjacent);
+        }
+
+        let edges: Vec<(usize, usize)> = cmd("SMEMBERS")
+            .arg(self.run_key(EDGES_KEY, run_id))
+            .query_async::<_, Vec<String>>(&mut conn)
+            .await?
+            .iter()
+            .map(|e| serde_json::from_str(e).unwrap())
+            .collect();
+
+        let mut pipe = pipe();
+        pipe.atomic();
+        for (up, down) in &edges {
+            pipe.cmd("SADD")
+                .arg(self.task_key(UPSTREAM_KEY, run_id, down))
+                .arg(up)
+                .ignore()
+                .cmd("SADD")
+                .arg(self.task_key(DOWNSTREAM_KEY, run_id, up))
+                .arg(down)
+                .ignore();
+        }
+        pipe.cmd("SET")
+            .arg(self.run_key(EDGES_INDEXED_KEY, run_id))
+            .arg(1)
+            .ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+
+        Ok(edges
+            .into_iter()
+            .filter_map(|(up, down)| match key {
+                UPSTREAM_KEY if down == task_id => Some(up),
+                DOWNSTREAM_KEY if up == task_id => Some(down),
+                _ => None,
+            })
+            .collect())
+    }
+
+    fn run_status_key(&self, dag_name: &str, status: RunStatus) -> String {
+        format!(
+            "{}:{}",
+            self.dag_key(RUN_STATUS_INDEX_KEY, dag_name),
+            status.as_str()
+        )
+    }
+
+    fn index_run(&self, pipe: &mut Pipeline, dag_name: &str, run: &Run, status: RunStatus) {
+        pipe.cmd("ZADD")
+            .arg(self.dag_key(RUN_INDEX_KEY, dag_name))
+            .arg(run.run_id)
+            .arg(run.run_id)
+            .ignore()
+            .cmd("ZADD")
+            .arg(self.dag_key(RUN_DATES_KEY, dag_name))
+            .arg(run.date.timestamp())
+            .arg(run.run_id)
+            .ignore()
+            .cmd("HSET")
+            .arg(self.dag_key(RUN_HASH_KEY, dag_name))
+            .arg(run.run_id)
+            .arg(serde_json::to_string(run).unwrap())
+            .ignore();
+        self.index_run_status(pipe, dag_name, run.run_id, status);
+    }
+
+    fn index_run_status(
+        &self,
+        pipe: &mut Pipeline,
+        dag_name: &str,
+        run_id: usize,
+        status: RunStatus,
+    ) {
+        for other in RunStatus::ALL {
+            if other == status {
+                pipe.cmd("ZADD")
+                    .arg(self.run_status_key(dag_name, other))
+                    .arg(run_id)
+                    .arg(run_id);
+            } else {
+                pipe.cmd("ZREM")
+                    .arg(self.run_status_key(dag_name, other))
+                    .arg(run_id);
+            }
+            pipe.ignore();
+        }
+    }
+
+    // the number of unfinished and failed tasks of a run, counted from their statuses
+    async fn count_task_statuses(&self, run_id: usize) -> StorageResult<(usize, usize)> {
+        let tasks = self.get_all_tasks(run_id).await?;
+        if tasks.is_empty() {
+            return Ok((0, 0));
+        }
+
+        let mut get = pipe();
+        for task in &tasks {
+            get.cmd("GET")
+                .arg(self.task_key(TASK_STATUS_KEY, run_id, task.id));
+        }
+        let mut conn = self.pool.get().await?;
+        let statuses: Vec<Option<String>> = get.query_async(&mut conn).await?;
+
+        let statuses: Vec<TaskStatus> = statuses
+            .iter()
+            .flatten()
+            .map(|status| TaskStatus::from_str(status).unwrap())
+            .collect();
+        Ok((
+            statuses.iter().filter(|s| is_unfinished(s)).count(),
+            statuses
+                .iter()
+                .filter(|s| matches!(s, TaskStatus::Failure))
+                .count(),
+        ))
+    }
+
+    // runs created before the run indexes existed are only in the `runs:{dag}` list, they're
+    // indexed on the dag's first listing
+    // the result event of the attempt, with the end of its stderr when it failed
+    async fn publish_task_result(
+        &self,
+        run_id: usize,
+        dag_name: &str,
+        result: &TaskResult,
+    ) -> StorageResult<()> {
+        let task_id = result.task_id;
+        let stderr_tail = match result.success {
+            true => vec![],
+            false => {
+                self.get_stderr_tail(
+                    run_id,
+                    task_id,
+                    result.attempt,
+                    STDERR_TAIL_LINES,
+                    STDERR_TAIL_SCAN,
+                )
+                .await?
+            }
+        };
+        self.publish_event(&Event::TaskResult {
+            dag_name: dag_name.to_string(),
+            run_id,
+            task_id,
+            attempt: result.attempt,
+            success: result.success,
+            stderr_tail,
+            truncated: self.get_truncated(run_id, task_id, result.attempt).await?,
+        })
+        .await
+    }
+
+    async fn index_legacy_runs(&self, dag_name: &str) -> StorageResult<()> {
+        let mut conn = self.pool.get().await?;
+        let indexed: bool = cmd("EXISTS")
+            .arg(self.dag_key(RUNS_INDEXED_KEY, dag_name))
+            .query_async(&mut conn)
+            .await?;
+        if indexed {
+            return Ok(());
+        }
+
+        for run in self.get_runs(dag_name).await? {
+            let (dag, unfinished, failed): (Option<String>, Option<usize>, Option<usize>) =
+                cmd("HMGET")
+                    .arg(self.run_key(RUN_COUNTS_KEY, run.run_id))
+                    .arg("dag")
+                    .arg("unfinished")
+                    .arg("failed")
+                    .query_async(&mut conn)
+                    .await?;
+
+            let status = if dag.is_some() {
+                RunStatus::from_counts(unfinished.unwrap_or(0), failed.unwrap_or(0))
+            } else {
+                let (unfinished, failed) = self.count_task_statuses(run.run_id).await?;
+                cmd("HSET")
+                    .arg(self.run_key(RUN_COUNTS_KEY, run.run_id))
+                    .arg("dag")
+                    .arg(dag_name)
+                    .arg("unfinished")
+                    .arg(unfinished)
+                    .arg("failed")
+                    .arg(failed)
+                    .query_async::<_, ()>(&mut conn)
+                    .await?;
+                RunStatus::from_counts(unfinished, failed)
+            };
+
+            let mut index = pipe();
+            index.atomic();
+            self.index_run(&mut index, dag_name, &run, status);
+            index.query_async::<_, ()>(&mut conn).await?;
+        }
+
+        cmd("SET")
+            .arg(self.dag_key(RUNS_INDEXED_KEY, dag_name))
+            .arg(1)
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    // run ids of a page in listing order
+    async fn list_run_ids(
+        &self,
+        conn: &mut RedisConnection,
+        dag_name: &str,
+        query: &RunQuery,
+        limit: usize,
+    ) -> StorageResult<Vec<usize>> {
+        if query.from.is_some() || query.to.is_some() {
+            return self
+                .list_run_ids_by_date(conn, dag_name, query, limit)
+                .await;
+        }
+
+        let key = match query.status {
+            Some(status) => self.run_status_key(dag_name, status),
+            None => self.dag_key(RUN_INDEX_KEY, dag_name),
+        };
+        let (command, start, end) = match query.order {
+            SortOrder::Asc => ("ZRANGEBYSCORE", "-inf", "+inf"),
+            SortOrder::Desc => ("ZREVRANGEBYSCORE", "+inf", "-inf"),
+        };
+        let start = query.cursor.map_or(start.to_string(), |c| format!("({c}"));
+
+        Ok(cmd(command)
+            .arg(key)
+            .arg(start)
+            .arg(end)
+            .arg("LIMIT")
+            .arg(0)
+            .arg(limit)
+            .query_async(conn)
+            .await?)
+    }
+
+    // a date range is read off `rd` in (logical date, run id) order a batch at a time, redis
+    // orders the runs of a second by their ids as strings
+    async fn list_run_ids_by_date(
+        &self,
+        conn: &mut RedisConnection,
+        dag_name: &str,
+        query: &RunQuery,
+        limit: usize,
+    ) -> StorageResult<Vec<usize>> {
+        let mut min = query.from.map(|from| from.timestamp());
+        let mut max = query.to.map(|to| to.timestamp());
+        // the page starts at the second of the cursor, past the runs up to the cursor
+        let after = query
+            .cursor
+            .zip(query.cursor_date)
+            .map(|(cursor, cursor_date)| {
+                let score = cursor_date.timestamp();
+                match query.order {
+                    SortOrder::Asc => min = Some(min.map_or(score, |min| min.max(score))),
+                    SortOrder::Desc => max = Some(max.map_or(score, |max| max.min(score))),
+                }
+                (score as f64, cursor.to_string())
+            });
+        if min.zip(max).is_some_and(|(min, max)| min > max) {
+            return Ok(vec![]);
+        }
+
+        let min = min.map_or("-inf".to_string(), |min| min.to_string());
+        let max = max.map_or("+inf".to_string(), |max| max.to_string());
+        let (command, start, end) = match query.order {
+            SortOrder::Asc => ("ZRANGEBYSCORE", min, max),
+            SortOrder::Desc => ("ZREVRANGEBYSCORE", max, min),
+        };
+
+        let mut run_ids = vec![];
+        let mut offset = 0;
+        while run_ids.len() < limit {
+            let batch: Vec<(String, f64)> = cmd(command)
+                .arg(self.dag_key(RUN_DATES_KEY, dag_name))
+                .arg(&start)
+                .arg(&end)
+                .arg("WITHSCORES")
+                .arg("LIMIT")
+                .arg(offset)
+                .arg(RUN_DATES_BATCH)
+                .query_async(conn)
+                .await?;
+            offset += batch.len();
+
+            let candidates: Vec<usize> = batch
+                .iter()
+                .filter(|(run_id, score)| match &after {
+                    Some((after_score, after_id)) if score == after_score => match query.order {
+                        SortOrder::Asc => run_id > after_id,
+                        SortOrder::Desc => run_id < after_id,
+                    },
+                    _ => true,
+                })
+                .map(|(run_id, _)| run_id.parse().unwrap())
+                .collect();
+
+            match query.status {
+                Some(status) if !candidates.is_empty() => {
+                    let mut scores = pipe();
+                    for run_id in &candidates {
+                        scores
+                            .cmd("ZSCORE")
+                            .arg(self.run_status_key(dag_name, status))
+                            .arg(run_id);
+                    }
+                    let scores: Vec<Option<f64>> = scores.query_async(conn).await?;
+                    run_ids.extend(
+                        candidates
+                            .into_iter()
+                            .zip(scores)
+                            .filter_map(|(run_id, score)| score.map(|_| run_id)),
+                    );
+                }
+                _ => run_ids.extend(candidates),
+            }
+
+            if batch.len() < RUN_DATES_BATCH {
+                break;
+            }
+        }
+
+        run_ids.truncate(limit);
+        Ok(run_ids)
+    }
+
+    // moves the tasks of a run stored with the legacy layout into the `tasks:{run_id}` hash,
+    // the per task keys are the up to date copies so they win over the set members
+    async fn migrate_legacy_tasks(&self, run_id: usize) -> StorageResult<()> {
+        let mut conn = self.pool.get().await?;
+        let migrated: bool = cmd("EXISTS")
+            .arg(self.run_key(TASKS_MIGRATED_KEY, run_id))
+            .query_async(&mut conn)
+            .await?;
+        if migrated {
+            return Ok(());
+        }
+
+        let task_ids: Vec<usize> = cmd("SMEMBERS")
+            .arg(self.run_key(LEGACY_TASKS_KEY, run_id))
+            .query_async::<_, Vec<String>>(&mut conn)
+            .await?
+            .iter()
+            .map(|t| serde_json::from_str::<Task>(t).unwrap().id)
+            .collect();
+
+        let mut get = pipe();
+        for task_id in &task_ids {
+            get.cmd("GET")
+                .arg(self.task_key(LEGACY_TASK_KEY, run_id, task_id));
+        }
+        let tasks: Vec<String> = get.query_async(&mut conn).await?;
+
+        let mut migrate = pipe();
+        migrate.atomic();
+        for (task_id, task) in task_ids.iter().zip(tasks) {
+            migrate
+                .cmd("HSET")
+                .arg(self.run_key(TASKS_KEY, run_id))
+                .arg(task_id)
+                .arg(task)
+                .ignore()
+                .cmd("DEL")
+                .arg(self.task_key(LEGACY_TASK_KEY, run_id, task_id))
+                .arg(self.task_key(LEGACY_TEMPLATE_ARGS_KEY, run_id, task_id))
+                .ignore();
+        }
+        Ok(migrate
+            .cmd("DEL")
+            .arg(self.run_key(LEGACY_TASKS_KEY, run_id))
+            .ignore()
+            .cmd("SET")
+            .arg(self.run_key(TASKS_MIGRATED_KEY, run_id))
+            .arg(1)
+            .ignore()
+            .query_async(&mut conn)
+            .await?)
+    }
+}
+
+#[async_trait]
+impl Storage for RedisStorage {
+    async fn create_new_run(
+        &self,
+        dag_name: &str,
+        dag_hash: &str,
+        logical_date: DateTime<Utc>,
+        data_interval: (DateTime<Utc>, DateTime<Utc>),
+    ) -> StorageResult<usize> {
+        let mut conn = self.pool.get().await?;
+
+        let run_id = cmd("INCR")
+            .arg(self.key("run"))
+            .query_async::<_, usize>(&mut conn)
+            .await?;
+
+        let run = Run {
+            run_id,
+            date: logical_date,
+            data_interval_start: Some(data_interval.0),
+            data_interval_end: Some(data_interval.1),
+        };
+        let serialized = serde_json::to_string(&run).unwrap();
+
+        // run and dag keys hash to different slots on a cluster, so they're written separately
+        pipe()
+            .atomic()
+            .cmd("SET")
+            .arg(self.run_key(RUN_KEY, run_id))
+            .arg(&serialized)
+            .ignore()
+            .cmd("SET")
+            .arg(self.run_key(EDGES_INDEXED_KEY, run_id))
+            .arg(1)
+            .ignore()
+            .cmd("SET")
+            .arg(self.run_key(TASKS_MIGRATED_KEY, run_id))
+            .arg(1)
+            .ignore()
+            .cmd("HSET")
+            .arg(self.run_key(RUN_COUNTS_KEY, run_id))
+            .arg("dag")
+            .arg(dag_name)
+            .arg("unfinished")
+            .arg(0)
+            .arg("failed")
+            .arg(0)
+            .ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+
+        let mut dag_pipe = pipe();
+        dag_pipe.atomic();
+        self.index_run(&mut dag_pipe, dag_name, &run, RunStatus::Running);
+        dag_pipe
+            .cmd("RPUSH")
+            .arg(self.dag_key(RUNS_KEY, dag_name))
+            .arg(serialized)
+            .ignore()
+            .cmd("SADD")
+            .arg(format!(
+                "{}:{dag_hash}",
+                self.dag_key(LOGICAL_DATES_KEY, dag_name)
+            ))
+            .arg(logical_date.to_string())
+            .ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+
+        log_publish_failure(
+            self.publish_event(&Event::RunCreated {
+                dag_name: dag_name.to_string(),
+                run_id,
+                logical_date,
+            })
+            .await,
+        );
+        Ok(run_id)
+    }
+
+    async fn get_run(&self, run_id: usize) -> StorageResult<Option<Run>> {
+        let mut conn = self.pool.get().await?;
+        Ok(cmd("GET")
+            .arg(self.run_key(RUN_KEY, run_id))
+            .query_async::<_, Option<String>>(&mut conn)
+            .await?
+            .map(|run| serde_json::from_str(&run).unwrap()))
+    }
+
+    async fn get_runs(&self, dag_name: &str) -> StorageResult<Vec<Run>> {
+        let mut conn = self.pool.get().await?;
+        Ok(cmd("LRANGE")
+            .arg(self.dag_key(RUNS_KEY, dag_name))
+            .arg(0)
+            .arg(-1)
+            .query_async::<_, Vec<String>>(&mut conn)
+            .await?
+            .iter()
+            .map(|v| serde_json::from_str(v).unwrap())
+            .collect())
+    }
+
+    async fn get_last_run(&self, dag_name: &str) -> StorageResult<Option<Run>> {
+        let mut conn = self.pool.get().await?;
+        Ok(cmd("LRANGE")
+            .arg(self.dag_key(RUNS_KEY, dag_name))
+            .arg(-1)
+            .arg(-1)
+            .query_async::<_, Vec<String>>(&mut conn)
+            .await?
+            .first()
+            .map(|run| serde_json::from_str(run).unwrap()))
+    }
+
+    async fn get_recent_runs(&self, dag_name: &str) -> StorageResult<Vec<Run>> {
+        let mut conn = self.pool.get().await?;
+        Ok(cmd("LRANGE")
+            .arg(self.dag_key(RUNS_KEY, dag_name))
+            .arg(-10)
+            .arg(-1)
+            .query_async::<_, Vec<String>>(&mut conn)
+            .await?
+            .iter()
+            .map(|run| serde_json::from_str(run).unwrap())
+            .collect())
+    }
+
+    async fn list_runs(
+        &self,
+        dag_name: &str,
+        query: &RunQuery,
+        limit: usize,
+    ) -> StorageResult<Vec<(Run, RunStatus)>> {
+        self.index_legacy_runs(dag_name).await?;
+
+        let mut conn = self.pool.get().await?;
+        let run_ids = self.list_run_ids(&mut conn, dag_name, query, limit).await?;
+        if run_ids.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let runs: Vec<Option<String>> = cmd("HMGET")
+            .arg(self.dag_key(RUN_HASH_KEY, dag_name))
+            .arg(&run_ids)
+            .query_async(&mut conn)
+            .await?;
+
+        let mut scores = pipe();
+        for run_id in &run_ids {
+            for status in RunStatus::ALL {
+                scores
+                    .cmd("ZSCORE")
+                    .arg(self.run_status_key(dag_name, status))
+                    .arg(run_id);
+            }
+        }
+        let scores: Vec<Option<f64>> = scores.query_async(&mut conn).await?;
+
+        Ok(runs
+            .into_iter()
+            .zip(scores.chunks(RunStatus::ALL.len()))
+            .filter_map(|(run, scores)| {
+                let status = RunStatus::ALL
+                    .into_iter()
+                    .zip(scores)
+                    .find_map(|(status, score)| score.map(|_| status))?;
+                Some((serde_json::from_str(&run?).unwrap(), status))
+            })
+            .collect())
+    }
+
+    async fn get_run_status(&self, run_id: usize) -> StorageResult<Option<RunStatus>> {
+        let mut conn = self.pool.get().await?;
+        let (exists, (dag, unfinished, failed)): (
+            bool,
+            (Option<String>, Option<usize>, Option<usize>),
+        ) = pipe()
+            .cmd("EXISTS")
+            .arg(self.run_key(RUN_KEY, run_id))
+            .cmd("HMGET")
+            .arg(self.run_key(RUN_COUNTS_KEY, run_id))
+            .arg("dag")
+            .arg("unfinished")
+            .arg("failed")
+            .query_async(&mut conn)
+            .await?;
+
+        if dag.is_some() {
+            Ok(Some(RunStatus::from_counts(
+                unfinished.unwrap_or(0),
+                failed.unwrap_or(0),
+            )))
+        } else if exists {
+            // created before the counts existed
+            let (unfinished, failed) = self.count_task_statuses(run_id).await?;
+            Ok(Some(RunStatus::from_counts(unfinished, failed)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn contains_logical_date(
+        &self,
+        dag_name: &str,
+        dag_hash: &str,
+        logical_date: DateTime<Utc>,
+    ) -> StorageResult<bool> {
+        let mut conn = self.pool.get().await?;
+        Ok(cmd("SISMEMBER")
+            .arg(format!(
+                "{}:{dag_hash}",
+                self.dag_key(LOGICAL_DATES_KEY, dag_name)
+            ))
+            .arg(logical_date.to_string())
+            .query_async(&mut conn)
+            .await?)
+    }
+
+    async fn is_run_of_dag(&self, dag_name: &str, run_id: usize) -> StorageResult<bool> {
+        self.index_legacy_runs(dag_name).await?;
+
+        let mut conn = self.pool.get().await?;
+        Ok(cmd("HEXISTS")
+            .arg(self.dag_key(RUN_HASH_KEY, dag_name))
+            .arg(run_id)
+            .query_async(&mut conn)
+            .await?)
+    }
+
+    async fn get_expired_run_ids(
+        &self,
+        dag_name: &str,
+        older_than: Option<DateTime<Utc>>,
+        keep: Option<usize>,
+    ) -> StorageResult<Vec<usize>> {
+        self.index_legacy_runs(dag_name).await?;
+
+        let mut conn = self.pool.get().await?;
+        let mut run_ids = vec![];
+        if let Some(older_than) = older_than {
+            run_ids.extend(
+                cmd("ZRANGEBYSCORE")
+                    .arg(self.dag_key(RUN_DATES_KEY, dag_name))
+                    .arg("-inf")
+                    .arg(format!("({}", older_than.timestamp()))
+                    .query_async::<_, Vec<usize>>(&mut conn)
+                    .await?,
+            );
+        }
+        // every run but the newest `keep` by rank
+        if let Some(keep) = keep {
+            run_ids.extend(
+                cmd("ZRANGE")
+                    .arg(self.dag_key(RUN_INDEX_KEY, dag_name))
+                    .arg(0)
+                    .arg(-(keep as i64) - 1)
+                    .query_async::<_, Vec<usize>>(&mut conn)
+                    .await?,
+            );
+        }
+
+        run_ids.sort_unstable();
+        run_ids.dedup();
+        Ok(run_ids)
+    }
+
+    async fn delete_run(&self, dag_name: &str, run_id: usize) -> StorageResult<()> {
+        let mut conn = self.pool.get().await?;
+        let (run, task_count): (Option<String>, Option<usize>) = pipe()
+            .cmd("GET")
+            .arg(self.run_key(RUN_KEY, run_id))
+            .cmd("GET")
+            .arg(self.run_key(TASK_ID_KEY, run_id))
+            .query_async(&mut conn)
+            .await?;
+        let task_ids = 0..task_count.unwrap_or(0);
+
+        let mut get_attempts = pipe();
+        for task_id in task_ids.clone() {
+            get_attempts
+                .cmd("GET")
+                .arg(self.task_key(TASK_ATTEMPT_KEY, run_id, task_id));
+        }
+        let attempts: Vec<Option<usize>> = get_attempts.query_async(&mut conn).await?;
+
+        let mut keys: Vec<String> = [
+            RUN_KEY,
+            TASK_ID_KEY,
+            TASKS_KEY,
+            DEPTH_KEY,
+            EDGES_KEY,
+            EDGES_INDEXED_KEY,
+            LEGACY_TASKS_KEY,
+            TASKS_MIGRATED_KEY,
+            RUN_COUNTS_KEY,
+        ]
+        .iter()
+        .map(|key| self.run_key(key, run_id))
+        .collect();
+        for (task_id, attempts) in task_ids.zip(attempts) {
+            for key in [
+                TASK_STATUS_KEY,
+                TASK_RESULTS_KEY,
+                TASK_RESULT_KEY,
+                TASK_ATTEMPT_KEY,
+                DEPENDENCY_KEYS_KEY,
+                UPSTREAM_KEY,
+                DOWNSTREAM_KEY,
+                LEGACY_TASK_KEY,
+                LEGACY_TEMPLATE_ARGS_KEY,
+            ] {
+                keys.push(self.task_key(key, run_id, task_id));
+            }
+            for attempt in 0..=attempts.unwrap_or(0) {
+                for key in [LOG_KEY, OUTPUT_KEY, TRUNCATED_KEY] {
+                    keys.push(format!("{}:{attempt}", self.task_key(key, run_id, task_id)));
+                }
+            }
+        }
+
+        cmd("DEL").arg(keys).query_async::<_, ()>(&mut conn).await?;
+
+        let mut dag_pipe = pipe();
+        dag_pipe.atomic();
+        if let Some(run) = run {
+            dag_pipe
+                .cmd("LREM")
+                .arg(self.dag_key(RUNS_KEY, dag_name))
+                .arg(0)
+                .arg(run)
+                .ignore();
+        }
+        for key in [RUN_INDEX_KEY, RUN_DATES_KEY] {
+            dag_pipe
+                .cmd("ZREM")
+                .arg(self.dag_key(key, dag_name))
+                .arg(run_id)
+                .ignore();
+        }
+        for status in RunStatus::ALL {
+            dag_pipe
+                .cmd("ZREM")
+                .arg(self.run_status_key(dag_name, status))
+                .arg(run_id)
+                .ignore();
+        }
+        dag_pipe
+            .cmd("HDEL")
+            .arg(self.dag_key(RUN_HASH_KEY, dag_name))
+            .arg(run_id)
+            .ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_next_task_ids(&self, run_id: usize, count: usize) -> StorageResult<Range<usize>> {
+        let mut conn = self.pool.get().await?;
+        let end = cmd("INCRBY")
+            .arg(self.run_key(TASK_ID_KEY, run_id))
+            .arg(count)
+            .query_async::<_, usize>(&mut conn)
+            .await?;
+        Ok(end - count..end)
+    }
+
+    async fn insert_tasks(&self, run_id: usize, tasks: &[Task]) -> StorageResult<()> {
+        if tasks.is_empty() {
+            return Ok(());
+        }
+
+        // a dynamic task appended to a run created before the upgrade must not start a new hash
+        self.migrate_legacy_tasks(run_id).await?;
+
+        let mut pipe = pipe();
+        pipe.atomic();
+        for task in tasks {
+            let task_id = task.id;
+
+            pipe.cmd("HSET")
+                .arg(self.run_key(TASKS_KEY, run_id))
+                .arg(task_id)
+                .arg(serde_json::to_string(task).unwrap())
+                .ignore()
+                .cmd("SET")
+                .arg(self.task_key(TASK_STATUS_KEY, run_id, task_id))
+                .arg(TaskStatus::Pending.as_str())
+                .ignore();
+        }
+        pipe.cmd("HINCRBY")
+            .arg(self.run_key(RUN_COUNTS_KEY, run_id))
+            .arg("unfinished")
+            .arg(tasks.len())
+            .ignore();
+
+        let mut conn = self.pool.get().await?;
+        Ok(pipe.query_async(&mut conn).await?)
+    }
+
+    async fn get_all_tasks(&self, run_id: usize) -> StorageResult<Vec<Task>> {
+        let get_all = || async {
+            let mut conn = self.pool.get().await?;
+            cmd("HGETALL")
+                .arg(self.run_key(TASKS_KEY, run_id))
+                .query_async::<_, HashMap<usize, String>>(&mut conn)
+                .await
+        };
+
+        let mut tasks = get_all().await?;
+        if tasks.is_empty() {
+            self.migrate_legacy_tasks(run_id).await?;
+            tasks = get_all().await?;
+        }
+
+        let mut tasks: Vec<Task> = tasks
+            .values()
+            .map(|t| serde_json::from_str(t).unwrap())
+            .collect();
+        tasks.sort_by_key(|task| task.id);
+        Ok(tasks)
+    }
+
+    async fn get_task_by_id(&self, run_id: usize, task_id: usize) -> StorageResult<Option<Task>> {
+        let get = || async {
+            let mut conn = self.pool.get().await?;
+            cmd("HGET")
+                .arg(self.run_key(TASKS_KEY, run_id))
+                .arg(task_id)
+                .query_async::<_, Option<String>>(&mut conn)
+                .await
+        };
+
+        let task = match get().await? {
+            Some(task) => Some(task),
+            None => {
+                self.migrate_legacy_tasks(run_id).await?;
+                get().await?
+            }
+        };
+        Ok(task.map(|task| serde_json::from_str(&task).unwrap()))
+    }
+
+    async fn set_template_args(
+        &self,
+        run_id: usize,
+        task_id: usize,
+        template_args: &Value,
+    ) -> StorageResult<()> {
+        let Some(mut task) = self.get_task_by_id(run_id, task_id).await? else {
+            return Ok(());
+        };
+        task.template_args = template_args.clone();
+
+        let mut conn = self.pool.get().await?;
+        cmd("HSET")
+            .arg(self.run_key(TASKS_KEY, run_id))
+            .arg(task_id)
+            .arg(serde_json::to_string(&task).unwrap())
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_task_depth(&self, run_id: usize, task_id: usize) -> StorageResult<Option<usize>> {
+        let mut conn = self.pool.get().await?;
+        Ok(cmd("HGET")
+            .arg(self.run_key(DEPTH_KEY, run_id))
+            .arg(task_id)
+            .query_async::<_, Option<usize>>(&mut conn)
+            .await?)
+    }
+
+    async fn get_task_depths(&self, run_id: usize) -> StorageResult<HashMap<usize, usize>> {
+        let mut conn = self.pool.get().await?;
+        Ok(cmd("HGETALL")
+            .arg(self.run_key(DEPTH_KEY, run_id))
+            .query_async::<_, HashMap<usize, usize>>(&mut conn)
+            .await?)
+    }
+
+    async fn set_task_depths(
+        &self,
+        run_id: usize,
+        depths: &HashMap<usize, usize>,
+    ) -> StorageResult<()> {
+        if depths.is_empty() {
+            return Ok(());
+        }
+
+        let mut conn = self.pool.get().await?;
+        let mut hset = cmd("HSET");
+        hset.arg(self.run_key(DEPTH_KEY, run_id));
+        for (task_id, depth) in depths {
+            hset.arg(task_id).arg(depth);
+        }
+        hset.query_async::<_, ()>(&mut conn).await?;
+        Ok(())
+    }
+
+    async fn delete_task_depth(&self, run_id: usize, task_id: usize) -> StorageResult<()> {
+        let mut conn = self.pool.get().await?;
+        cmd("HDEL")
+            .arg(self.run_key(DEPTH_KEY, run_id))
+            .arg(task_id)
+            .query_async::<_, usize>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_task_status(
+        &self,
+        run_id: usize,
+        task_id: usize,
+    ) -> StorageResult<Option<TaskStatus>> {
+        let mut conn = self.pool.get().await?;
+        Ok(cmd("GET")
+            .arg(self.task_key(TASK_STATUS_KEY, run_id, task_id))
+            .query_async::<_, Option<String>>(&mut conn)
+            .await?
+            .map(|status| TaskStatus::from_str(&status).unwrap()))
+    }
+
+    async fn set_task_status(
+        &self,
+        run_id: usize,
+        task_id: usize,
+        task_status: TaskStatus,
+    ) -> StorageResult<()> {
+        let mut conn = self.pool.get().await?;
+        let (dag, unfinished, failed): (Option<String>, Option<usize>, Option<usize>) =
+            Script::new(SET_TASK_STATUS_SCRIPT)
+                .key(self.task_key(TASK_STATUS_KEY, run_id, task_id))
+                .key(self.run_key(RUN_COUNTS_KEY, run_id))
+                .arg(task_status.as_str())
+                .arg(TaskStatus::Failure.as_str())
+                .arg(&unfinished_statuses()[..])
+                .invoke_async(&mut conn)
+                .await?;
+
+        // runs created before the counts existed are indexed by `index_legacy_runs`
+        let Some(dag_name) = dag else {
+            return Ok(());
+        };
+        let status = RunStatus::from_counts(unfinished.unwrap_or(0), failed.unwrap_or(0));
+        let mut index = pipe();
+        index.atomic();
+        self.index_run_status(&mut index, &dag_name, run_id, status);
+        index.query_async::<_, ()>(&mut conn).await?;
+
+        log_publish_failure(
+            self.publish_event(&Event::TaskStatus {
+                dag_name,
+                run_id,
+                task_id,
+                status: task_status.as_str().to_string(),
+                run_status: status,
+            })
+            .await,
+        );
+        Ok(())
+    }
+
+    async fn increment_attempt(&self, run_id: usize, task_id: usize) -> StorageResult<usize> {
+        let mut conn = self.pool.get().await?;
+        Ok(cmd("INCR")
+            .arg(self.task_key(TASK_ATTEMPT_KEY, run_id, task_id))
+            .query_async::<_, usize>(&mut conn)
+            .await?)
+    }
+
+    async fn get_attempt(&self, run_id: usize, task_id: usize) -> StorageResult<usize> {
+        let mut conn = self.pool.get().await?;
+        Ok(cmd("GET")
+            .arg(self.task_key(TASK_ATTEMPT_KEY, run_id, task_id))
+            .query_async::<_, Option<usize>>(&mut conn)
+            .await?
+            .unwrap_or(0))
+    }
+
+    async fn insert_task_results(&self, run_id: usize, result: &TaskResult) -> StorageResult<()> {
+        let mut conn = self.pool.get().await?;
+        let res = serde_json::to_string(result).unwrap();
+        let task_id = result.task_id;
+
+        let (dag_name,): (Option<String>,) = pipe()
+            .atomic()
+            .cmd("RPUSH")
+            .arg(self.task_key(TASK_RESULTS_KEY, run_id, task_id))
+            .arg(&res)
+            .ignore()
+            .cmd("SET")
+            .arg(self.task_key(TASK_RESULT_KEY, run_id, task_id))
+            .arg(res)
+            .ignore()
+            .cmd("HGET")
+            .arg(self.run_key(RUN_COUNTS_KEY, run_id))
+            .arg("dag")
+            .query_async(&mut conn)
+            .await?;
+
+        if let Some(dag_name) = dag_name {
+            log_publish_failure(self.publish_task_result(run_id, &dag_name, result).await);
+        }
+        Ok(())
+    }
+
+    async fn get_task_result(
+        &self,
+        run_id: usize,
+        task_id: usize,
+    ) -> StorageResult<Option<TaskResult>> {
+        let mut conn = self.pool.get().await?;
+        Ok(cmd("GET")
+            .arg(self.task_key(TASK_RESULT_KEY, run_id, task_id))
+            .query_async::<_, Option<String>>(&mut conn)
+            .await?
+            .map(|result| serde_json::from_str(&result).unwrap()))
+    }
+
+    async fn get_all_results(
+        &self,
+        run_id: usize,
+        task_id: usize,
+    ) -> StorageResult<Vec<TaskResult>> {
+        let mut conn = self.pool.get().await?;
+        Ok(cmd("LRANGE")
+            .arg(self.task_key(TASK_RESULTS_KEY, run_id, task_id))
+            .arg(0)
+            .arg(-1)
+            .query_async::<_, Vec<String>>(&mut conn)
+            .await?
+            .iter()
+            .map(|v| serde_json::from_str(v).unwrap())
+            .collect())
+    }
+
+    async fn get_dependency_keys(
+        &self,
+        run_id: usize,
+        task_id: usize,
+    ) -> StorageResult<HashMap<(usize, String), String>> {
+        let mut conn = self.pool.get().await?;
+        let k: Vec<((usize, String), String)> = cmd("SMEMBERS")
+            .arg(self.task_key(DEPENDENCY_KEYS_KEY, run_id, task_id))
+            .query_async::<_, Vec<String>>(&mut conn)
+            .await?
+            .iter()
+            .map(|v| serde_json::from_str(v).unwrap())
+            .collect();
+        Ok(k.into_iter().collect())
+    }
+
+    async fn set_dependency_keys(
+        &self,
+        run_id: usize,
+        task_id: usize,
+        upstream: (usize, String),
+        v: String,
+    ) -> StorageResult<()> {
+        let mut conn = self.pool.get().await?;
+        cmd("SADD")
+            .arg(self.task_key(DEPENDENCY_KEYS_KEY, run_id, task_id))
+            .arg(serde_json::to_string(&(upstream, v)).unwrap())
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_log_line_count(
+        &self,
+        run_id: usize,
+        task_id: usize,
+        attempt: usize,
+    ) -> StorageResult<usize> {
+        let mut conn = self.pool.get().await?;
+        Ok(cmd("LLEN")
+            .arg(format!(
+                "{}:{attempt}",
+                self.task_key(LOG_KEY, run_id, task_id)
+            ))
+            .query_async(&mut conn)
+            .await?)
+    }
+
+    async fn get_log_lines(
+        &self,
+        run_id: usize,
+        task_id: usize,
+        attempt: usize,
+        offset: usize,
+        limit: usize,
+    ) -> StorageResult<Vec<LogLine>> {
+        if limit == 0 {
+            return Ok(vec![]);
+        }
+
+        let mut conn = self.pool.get().await?;
+        Ok(cmd("LRANGE")
+            .arg(format!(
+                "{}:{attempt}",
+                self.task_key(LOG_KEY, run_id, task_id)
+            ))
+            .arg(offset)
+            .arg(offset + limit - 1)
+            .query_async::<_, Vec<String>>(&mut conn)
+            .await?
+            .iter()
+            .map(|entry| LogLine::parse(entry))
+            .collect())
+    }
+
+    async fn append_log(
+        &self,
+        run_id: usize,
+        task_id: usize,
+        attempt: usize,
+        line: &LogLine,
+    ) -> StorageResult<()> {
+        let mut pipe = pipe();
+        pipe.atomic()
+            .cmd("RPUSH")
+            .arg(format!(
+                "{}:{attempt}",
+                self.task_key(LOG_KEY, run_id, task_id)
+            ))
+            .arg(line.to_entry())
+            .ignore();
+        if line.stream == LogStream::Stdout {
+            pipe.cmd("SET")
+                .arg(format!(
+                    "{}:{attempt}",
+                    self.task_key(OUTPUT_KEY, run_id, task_id)
+                ))
+                .arg(&line.line)
+                .ignore();
+        }
+
+        let mut conn = self.pool.get().await?;
+        pipe.query_async::<_, ()>(&mut conn).await?;
+        Ok(())
+    }
+
+    async fn get_last_output(
+        &self,
+        run_id: usize,
+        task_id: usize,
+        attempt: usize,
+    ) -> StorageResult<Option<String>> {
+        let mut conn = self.pool.get().await?;
+        // attempts started before outputs were kept only have plain lines in their log
+        let (output, last_line): (Option<String>, Option<String>) = pipe()
+            .cmd("GET")
+            .arg(format!(
+                "{}:{attempt}",
+                self.task_key(OUTPUT_KEY, run_id, task_id)
+            ))
+            .cmd("LINDEX")
+            .arg(format!(
+                "{}:{attempt}",
+                self.task_key(LOG_KEY, run_id, task_id)
+            ))
+            .arg(-1)
+            .query_async(&mut conn)
+            .await?;
+        Ok(output.or(last_line
+            .map(|entry| LogLine::parse(&entry))
+            .filter(|line| line.timestamp.is_none())
+            .map(|line| line.line)))
+    }
+
+    async fn set_last_output(
+        &self,
+        run_id: usize,
+        task_id: usize,
+        attempt: usize,
+        line: &str,
+    ) -> StorageResult<()> {
+        let mut conn = self.pool.get().await?;
+        cmd("SET")
+            .arg(format!(
+                "{}:{attempt}",
+                self.task_key(OUTPUT_KEY, run_id, task_id)
+            ))
+            .arg(line)
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_truncated(
+        &self,
+        run_id: usize,
+        task_id: usize,
+        attempt: usize,
+        truncated: Truncated,
+    ) -> StorageResult<()> {
+        let mut conn = self.pool.get().await?;
+        cmd("SADD")
+            .arg(format!(
+                "{}:{attempt}",
+                self.task_key(TRUNCATED_KEY, run_id, task_id)
+            ))
+            .arg(truncated.as_str())
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_truncated(
+        &self,
+        run_id: usize,
+        task_id: usize,
+        attempt: usize,
+    ) -> StorageResult<Vec<Truncated>> {
+        let mut conn = self.pool.get().await?;
+        Ok(cmd("SMEMBERS")
+            .arg(format!(
+                "{}:{attempt}",
+                self.task_key(TRUNCATED_KEY, run_id, task_id)
+            ))
+            .query_async::<_, Vec<String>>(&mut conn)
+            .await?
+            .iter()
+            .filter_map(|truncated| Truncated::from_str(truncated).ok())
+            .collect())
+    }
+
+    async fn get_edges(&self, run_id: usize) -> StorageResult<HashSet<(usize, usize)>> {
+        let mut conn = self.pool.get().await?;
+        Ok(cmd("SMEMBERS")
+            .arg(self.run_key(EDGES_KEY, run_id))
+            .query_async::<_, Vec<String>>(&mut conn)
+            .await?
+            .iter()
+            .map(|e| serde_json::from_str(e).unwrap())
+            .collect())
+    }
+
+    async fn get_downstream(&self, run_id: usize, task_id: usize) -> StorageResult<Vec<usize>> {
+        self.get_adjacent(run_id, DOWNSTREAM_KEY, task_id).await
+    }
+
+    async fn get_upstream(&self, run_id: usize, task_id: usize) -> StorageResult<Vec<usize>> {
+        self.get_adjacent(run_id, UPSTREAM_KEY, task_id).await
+    }
+
+    async fn insert_edge(&self, run_id: usize, edge: (usize, usize)) -> StorageResult<()> {
+        let mut conn = self.pool.get().await?;
+        pipe()
+            .atomic()
+            .cmd("SADD")
+            .arg(self.run_key(EDGES_KEY, run_id))
+            .arg(serde_json::to_string(&edge).unwrap())
+            .ignore()
+            .cmd("SADD")
+            .arg(self.task_key(UPSTREAM_KEY, run_id, edge.1))
+            .arg(edge.0)
+            .ignore()
+            .cmd("SADD")
+            .arg(self.task_key(DOWNSTREAM_KEY, run_id, edge.0))
+            .arg(edge.1)
+            .ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_edge(&self, run_id: usize, edge: (usize, usize)) -> StorageResult<()> {
+        let mut conn = self.pool.get().await?;
+        pipe()
+            .atomic()
+            .cmd("SREM")
+            .arg(self.run_key(EDGES_KEY, run_id))
+            .arg(serde_json::to_string(&edge).unwrap())
+            .ignore()
+            .cmd("SREM")
+            .arg(self.task_key(UPSTREAM_KEY, run_id, edge.1))
+            .arg(edge.0)
+            .ignore()
+            .cmd("SREM")
+            .arg(self.task_key(DOWNSTREAM_KEY, run_id, edge.0))
+            .arg(edge.1)
+            .ignore()
+            .cmd("SREM")
+            .arg(self.task_key(DEPENDENCY_KEYS_KEY, run_id, edge.1))
+            .arg(serde_json::to_string(&((edge.0, ""), "")).unwrap())
+            .ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn enqueue_task(&self, queued_task: &QueuedTask, depth: usize) -> StorageResult<()> {
+        let mut conn = self.pool.get().await?;
+        cmd("ZADD")
+            .arg(self.queue_key(QUEUE_KEY))
+            .arg(depth)
+            .arg(serde_json::to_string(queued_task).unwrap())
+            .query_async::<_, usize>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn pop_priority_queue(
+        &self,
+        max_threads: usize,
+    ) -> StorageResult<Option<OrderedQueuedTask>> {
+        let mut conn = self.pool.get().await?;
+
+        let vec = Script::new(POP_PRIORITY_QUEUE_SCRIPT)
+            .key(self.queue_key(QUEUE_KEY))
+            .key(self.queue_key(TEMP_QUEUE_KEY))
+            .arg(max_threads) // TODO timeout arg
+            .invoke_async::<_, Vec<String>>(&mut conn)
+            .await?;
+
+        if vec.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(OrderedQueuedTask {
+            score: vec[1].parse().unwrap(),
+            queued_task: serde_json::from_str(&vec[0]).unwrap(),
+        }))
+    }
+
+    async fn get_temp_queue(&self) -> StorageResult<Vec<QueuedTask>> {
+        let mut conn = self.pool.get().await?;
+        Ok(cmd("SMEMBERS")
+            .arg(self.queue_key(TEMP_QUEUE_KEY)) // TODO timeout arg
+            .query_async::<_, Vec<String>>(&mut conn)
+            .await?
+            .iter()
+            .map(|s| serde_json::from_str(s).unwrap())
+            .collect())
+    }
+
+    async fn remove_from_temp_queue(&self, queued_task: &QueuedTask) -> StorageResult<()> {
+        let mut conn = self.pool.get().await?;
+        cmd("SREM")
+            .arg(self.queue_key(TEMP_QUEUE_KEY)) // TODO timeout arg
+            .arg(serde_json::to_string(queued_task).unwrap())
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_next_backfill_id(&self) -> StorageResult<usize> {
+        let mut conn = self.pool.get().await?;
+        Ok(cmd("INCR")
+            .arg(self.key(BACKFILL_ID_KEY))
+            .query_async::<_, usize>(&mut conn)
+            .await?)
+    }
+
+    async fn set_backfill(&self, backfill: &Backfill) -> StorageResult<()> {
+        let mut conn = self.pool.get().await?;
+        let backfill_id = backfill.backfill_id;
+        let dag_name = &backfill.dag_name;
+
+        // separate commands since the two keys hash to different slots on a cluster
+        cmd("SET")
+            .arg(self.key(format!("{BACKFILL_KEY}:{backfill_id}")))
+            .arg(serde_json::to_string(backfill).unwrap())
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        cmd("SADD")
+            .arg(self.dag_key(BACKFILLS_KEY, dag_name))
+            .arg(backfill_id)
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_backfill(&self, backfill_id: usize) -> StorageResult<Option<Backfill>> {
+        let mut conn = self.pool.get().await?;
+        Ok(cmd("GET")
+            .arg(self.key(format!("{BACKFILL_KEY}:{backfill_id}")))
+            .query_async::<_, Option<String>>(&mut conn)
+            .await?
+            .map(|b| serde_json::from_str(&b).unwrap()))
+    }
+
+    async fn get_backfills(&self, dag_name: &str) -> StorageResult<Vec<Backfill>> {
+        let mut conn = self.pool.get().await?;
+        let mut backfill_ids = cmd("SMEMBERS")
+            .arg(self.dag_key(BACKFILLS_KEY, dag_name))
+            .query_async::<_, Vec<usize>>(&mut conn)
+            .await?;
+        backfill_ids.sort();
+
+        let mut backfills = vec![];
+        for backfill_id in backfill_ids {
+            if let Some(backfill) = self.get_backfill(backfill_id).await? {
+                backfills.push(backfill);
+            }
+        }
+        Ok(backfills)
+    }
+
+    async fn publish_event(&self, event: &Event) -> StorageResult<()> {
+        let mut conn = self.pool.get().await?;
+        cmd("PUBLISH")
+            .arg(self.key(EVENTS_CHANNEL))
+            .arg(serde_json::to_string(event).unwrap())
+            .query_async::<_, usize>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn subscribe_events(&self) -> StorageResult<EventStream> {
+        let mut pubsub = self.pool.get_pubsub().await?;
+        pubsub.subscribe(self.key(EVENTS_CHANNEL)).await?;
+        Ok(pubsub
+            .into_on_message()
+            .filter_map(|msg| {
+                future::ready(
+                    msg.get_payload::<String>()
+                        .ok()
+                        .and_then(|event| serde_json::from_str(&event).ok()),
+                )
+            })
+            .boxed())
+    }
+}
@@ -0,0 +1,579 @@
+use log::{debug, warn};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    future::Future,
+    panic,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use chrono::{DateTime, Utc};
+use thepipelinetool::server::*;
+use timed::timed;
+use tokio::runtime::Handle;
+
+use crate::{
+    limits::{
+        get_max_log_bytes, get_max_result_bytes, log_truncated_marker, prefix, truncate_output,
+        truncate_result,
+    },
+    redact::get_redactor,
+    statics::{_get_default_edges, _get_default_tasks, _get_options},
+    storage::{LogLine, LogStream, Run, Storage, StorageError, StorageResult, Truncated},
+};
+
+pub const MAX_THREADS: usize = 10;
+
+// the Runner trait is synchronous, so its methods must only be called off the runtime threads,
+// use `blocking` to get there from async code. it has no way to report errors either, until it
+// does the first storage error is kept here and returned by `blocking`, see `block_on`
+pub struct StorageRunner {
+    edges: HashSet<(usize, usize)>,
+    nodes: Vec<Task>,
+    name: String,
+    storage: Arc<dyn Storage>,
+    handle: Handle,
+    log_handles: HashMap<(usize, usize, usize), AttemptLog>,
+    // the run tasks were last appended to, a dynamic expansion appends its tasks one by one
+    last_run: Option<Run>,
+    // the first storage error, shared with the log handles
+    failure: Arc<Mutex<Option<StorageError>>>,
+}
+
+// the log handles given out for an attempt and the bytes they wrote together
+#[derive(Default)]
+struct AttemptLog {
+    handles: usize,
+    written: Arc<AtomicUsize>,
+}
+
+impl StorageRunner {
+    #[timed(duration(printer = "debug!"))]
+    pub fn dummy(storage: Arc<dyn Storage>) -> Self {
+        Self {
+            name: "".into(),
+            edges: HashSet::new(),
+            nodes: vec![],
+            storage,
+            handle: Handle::current(),
+            log_handles: HashMap::new(),
+            last_run: None,
+            failure: Arc::default(),
+        }
+    }
+
+    #[timed(duration(printer = "debug!"))]
+    pub fn from_local_dag(name: &str, storage: Arc<dyn Storage>) -> Self {
+        let nodes = _get_default_tasks(name);
+        let edges = _get_default_edges(name);
+
+        Self {
+            name: name.into(),
+            edges,
+            nodes,
+            storage,
+            handle: Handle::current(),
+            log_handles: HashMap::new(),
+            last_run: None,
+            failure: Arc::default(),
+        }
+    }
+
+    // runs synchronous runner logic (e.g. `BlanketRunner`) on the blocking pool, the first
+    // storage failure inside `f` is returned as the error and whatever `f` returned is dropped
+    pub async fn blocking<T, F>(mut self, f: F) -> StorageResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Self) -> T + Send + 'static,
+    {
+        let failure = self.failure.clone();
+        let result = tokio::task::spawn_blocking(move || f(&mut self)).await;
+        // a read the runner can't go on without unwinds once storage failed, see `get_task_by_id`
+        if let Some(err) = failure.lock().unwrap().take() {
+            return Err(err);
+        }
+        match result {
+            Ok(value) => Ok(value),
+            Err(err) => panic::resume_unwind(err.into_panic()),
+        }
+    }
+
+    fn block_on<T: Default>(&self, future: impl Future<Output = StorageResult<T>>) -> T {
+        block_on(&self.handle, &self.failure, future)
+    }
+
+    // `BlanketRunner::enqueue_run` with the default tasks created in one bulk write instead of
+    // one `append_new_task_and_set_status_to_pending` each
+    #[timed(duration(printer = "debug!"))]
+    pub fn enqueue_new_run(
+        &mut self,
+        dag_name: &str,
+        dag_hash: &str,
+        logical_date: DateTime<Utc>,
+    ) -> usize {
+        let run_id = self.create_new_run(dag_name, dag_hash, logical_date);
+
+        // a new run allocates task ids from 0, so they match the ids of the default edges
+        let task_ids = self.append_new_tasks_and_set_status_to_pending(run_id, self.nodes.clone());
+        for edge in self.get_default_edges() {
+            self.insert_edge(run_id, edge);
+        }
+        for task_id in task_ids {
+            self.enqueue_task(run_id, task_id);
+        }
+
+        run_id
+    }
+
+    // bulk version of `append_new_task_and_set_status_to_pending`, the ids of the given tasks
+    // are replaced with freshly allocated ones which are returned in order
+    #[timed(duration(printer = "debug!"))]
+    pub fn append_new_tasks_and_set_status_to_pending(
+        &mut self,
+        run_id: usize,
+        tasks: Vec<Task>,
+    ) -> Vec<usize> {
+        let run = match self.last_run.take() {
+            Some(run) if run.run_id == run_id => Some(run),
+            _ => self.block_on(self.storage.get_run(run_id)),
+        };
+
+        let ids = self.block_on(async {
+            let ids = self.storage.get_next_task_ids(run_id, tasks.len()).await?;
+
+            let tasks: Vec<Task> = tasks
+                .into_iter()
+                .zip(ids)
+                .map(|(mut task, id)| {
+                    if let Some(run) = &run {
+                        task.template_args = run.render_template_args(&task.template_args);
+                    }
+                    task.id = id;
+                    task
+                })
+                .collect();
+            self.storage.insert_tasks(run_id, &tasks).await?;
+            Ok(tasks.iter().map(|task| task.id).collect())
+        });
+
+        self.last_run = run;
+        ids
+    }
+}
+
+impl Runner for StorageRunner {
+    #[timed(duration(printer = "debug!"))]
+    fn remove_from_temp_queue(&self, queued_task: &QueuedTask) {
+        self.block_on(self.storage.remove_from_temp_queue(queued_task))
+    }
+
+    fn delete_task_depth(&mut self, run_id: usize, task_id: usize) {
+        self.block_on(self.storage.delete_task_depth(run_id, task_id))
+    }
+
+    #[timed(duration(printer = "debug!"))]
+    fn get_log(&mut self, run_id: usize, task_id: usize, attempt: usize) -> String {
+        self.block_on(self.storage.get_log(run_id, task_id, attempt))
+    }
+
+    #[timed(duration(printer = "debug!"))]
+    fn get_log_handle_closure(
+        &mut self,
+        run_id: usize,
+        task_id: usize,
+        attempt: usize,
+    ) -> Box<dyn Fn(String) + Send> {
+        // the runner doesn't say which stream a handle is for, thepipelinetool asks for the
+        // stdout handle of an attempt first and for its stderr one second, see Cargo.toml
+        let log = self
+            .log_handles
+            .entry((run_id, task_id, attempt))
+            .or_default();
+        // anything past the second handle is logged as stderr rather than failing the attempt
+        if log.handles >= 2 {
+            warn!(
+                "more than two log handles asked for attempt {attempt} of task {task_id} of run {run_id}"
+            );
+        }
+        let stream = if log.handles == 0 {
+            LogStream::Stdout
+        } else {
+            LogStream::Stderr
+        };
+        log.handles += 1;
+
+        let written = log.written.clone();
+        let max_log_bytes = get_max_log_bytes();
+        let storage = self.storage.clone();
+        let handle = self.handle.clone();
+        let failure = self.failure.clone();
+        Box::new(move |s: String| {
+            let line = LogLine::new(stream, get_redactor().redact(&s));
+            let written = written.fetch_add(line.line.len(), Ordering::SeqCst);
+            block_on(
+                &handle,
+                &failure,
+                append_capped_log(
+                    run_id,
+                    task_id,
+                    attempt,
+                    line,
+                    written,
+                    max_log_bytes,
+                    storage.as_ref(),
+                ),
+            )
+        })
+    }
+
+    #[timed(duration(printer = "debug!"))]
+    fn get_dag_name(&self) -> String {
+        self.name.clone()
+    }
+
+    #[timed(duration(printer = "debug!"))]
+    fn get_task_result(&mut self, run_id: usize, task_id: usize) -> TaskResult {
+        self.block_on(self.storage.get_task_result(run_id, task_id))
+            .unwrap()
+    }
+
+    #[timed(duration(printer = "debug!"))]
+    fn get_attempt_by_task_id(&self, run_id: usize, task_id: usize) -> usize {
+        self.block_on(self.storage.increment_attempt(run_id, task_id))
+    }
+
+    #[timed(duration(printer = "debug!"))]
+    fn get_task_status(&mut self, run_id: usize, task_id: usize) -> TaskStatus {
+        self.block_on(self.storage.get_task_status(run_id, task_id))
+            .unwrap()
+    }
+
+    #[timed(duration(printer = "debug!"))]
+    fn set_task_status(&mut self, run_id: usize, task_id: usize, task_status: TaskStatus) {
+        self.block_on(self.storage.set_task_status(run_id, task_id, task_status))
+    }
+
+    #[timed(duration(printer = "debug!"))]
+    fn create_new_run(
+        &mut self,
+        dag_name: &str,
+        dag_hash: &str,
+        logical_date: DateTime<Utc>,
+    ) -> usize {
+        let data_interval = _get_options(dag_name).get_data_interval(logical_date);
+
+        self.block_on(
+            self.storage
+                .create_new_run(dag_name, dag_hash, logical_date, data_interval),
+        )
+    }
+
+    #[timed(duration(printer = "debug!"))]
+    fn insert_task_results(&mut self, run_id: usize, result: &TaskResult) {
+        let result = get_redactor().redact_result(result);
+        // the attempt is over, its log handles are not asked for again
+        self.log_handles
+            .remove(&(run_id, result.task_id, result.attempt));
+        self.block_on(async {
+            match truncate_result(&result, get_max_result_bytes()) {
+                Some(truncated) => {
+                    self.storage
+                        .set_truncated(run_id, result.task_id, result.attempt, Truncated::Result)
+                        .await?;
+                    self.storage.insert_task_results(run_id, &truncated).await
+                }
+                None => self.storage.insert_task_results(run_id, &result).await,
+            }
+        })
+    }
+
+    #[timed(duration(printer = "debug!"))]
+    fn get_dependency_keys(
+        &mut self,
+        run_id: usize,
+        task_id: usize,
+    ) -> HashMap<(usize, String), String> {
+        self.block_on(self.storage.get_dependency_keys(run_id, task_id))
+    }
+
+    #[timed(duration(printer = "debug!"))]
+    fn set_dependency_keys(
+        &mut self,
+        run_id: usize,
+        task_id: usize,
+        upstream: (usize, String),
+        v: String,
+    ) {
+        self.block_on(
+            self.storage
+                .set_dependency_keys(run_id, task_id, upstream, v),
+        )
+    }
+
+    #[timed(duration(printer = "debug!"))]
+    fn get_downstream(&self, run_id: usize, task_id: usize) -> Vec<usize> {
+        self.block_on(self.storage.get_downstream(run_id, task_id))
+    }
+
+    #[timed(duration(printer = "debug!"))]
+    fn get_upstream(&self, run_id: usize, task_id: usize) -> Vec<usize> {
+        self.block_on(self.storage.get_upstream(run_id, task_id))
+    }
+
+    #[timed(duration(printer = "debug!"))]
+    fn remove_edge(&mut self, run_id: usize, edge: (usize, usize)) {
+        self.block_on(self.storage.remove_edge(run_id, edge))
+    }
+
+    #[timed(duration(printer = "debug!"))]
+    fn insert_edge(&mut self, run_id: usize, edge: (usize, usize)) {
+        self.block_on(self.storage.insert_edge(run_id, edge))
+    }
+
+    #[timed(duration(printer = "debug!"))]
+    fn get_default_tasks(&self) -> Vec<Task> {
+        self.nodes.clone()
+    }
+
+    #[timed(duration(printer = "debug!"))]
+    fn get_all_tasks(&self, run_id: usize) -> Vec<Task> {
+        self.block_on(self.storage.get_all_tasks(run_id))
+    }
+
+    #[timed(duration(printer = "debug!"))]
+    fn get_default_edges(&self) -> HashSet<(usize, usize)> {
+        self.edges.clone()
+    }
+
+    #[timed(duration(printer = "debug!"))]
+    fn get_task_by_id(&self, run_id: usize, task_id: usize) -> Task {
+        self.block_on(self.storage.get_task_by_id(run_id, task_id))
+            .unwrap()
+    }
+
+    #[timed(duration(printer = "debug!"))]
+    fn append_new_task_and_set_status_to_pending(
+        &mut self,
+        run_id: usize,
+        function_name: &str,
+        template_args: &Value,
+        options: &TaskOptions,
+        lazy_expand: bool,
+        is_dynamic: bool,
+        is_branch: bool,
+    ) -> usize {
+        let task = Task {
+            id: 0,
+            function_name: function_name.to_owned(),
+            template_args: template_args.to_owned(),
+            options: options.to_owned(),
+            lazy_expand,
+            is_dynamic,
+            is_branch,
+        };
+        self.append_new_tasks_and_set_status_to_pending(run_id, vec![task])
+            .first()
+            .copied()
+            .unwrap_or_default()
+    }
+
+    #[timed(duration(printer = "debug!"))]
+    fn get_template_args(&self, run_id: usize, task_id: usize) -> serde_json::Value {
+        let task = self.get_task_by_id(run_id, task_id);
+        task.template_args
+    }
+
+    #[timed(duration(printer = "debug!"))]
+    fn set_template_args(&mut self, run_id: usize, task_id: usize, template_args_str: &str) {
+        self.block_on(self.storage.set_template_args(
+            run_id,
+            task_id,
+            &serde_json::from_str(template_args_str).unwrap(),
+        ))
+    }
+
+    #[timed(duration(printer = "debug!"))]
+    fn pop_priority_queue(&mut self) -> Option<OrderedQueuedTask> {
+        self.block_on(self.storage.pop_priority_queue(MAX_THREADS))
+    }
+
+    fn get_task_depth(&mut self, run_id: usize, task_id: usize) -> usize {
+        if let Some(depth) = self.block_on(self.storage.get_task_depth(run_id, task_id)) {
+            return depth;
+        }
+
+        // the first miss of a run (its first enqueue) computes every depth at once, later misses
+        // only fill in the tasks appended or invalidated since
+        let (edges, mut depths) = self.block_on(async {
+            Ok((
+                self.storage.get_edges(run_id).await?,
+                self.storage.get_task_depths(run_id).await?,
+            ))
+        });
+        let mut missing = compute_missing_depths(&edges, &mut depths);
+        let depth = *missing.entry(task_id).or_insert(0);
+
+        self.block_on(self.storage.set_task_depths(run_id, &missing));
+        depth
+    }
+
+    #[timed(duration(printer = "debug!"))]
+    fn set_task_depth(&mut self, run_id: usize, task_id: usize, depth: usize) {
+        self.block_on(
+            self.storage
+                .set_task_depths(run_id, &HashMap::from([(task_id, depth)])),
+        )
+    }
+
+    #[timed(duration(printer = "debug!"))]
+    fn enqueue_task(&mut self, run_id: usize, task_id: usize) {
+        let attempt: usize = self.get_attempt_by_task_id(run_id, task_id);
+        let depth = self.get_task_depth(run_id, task_id);
+
+        self.block_on(self.storage.enqueue_task(
+            &QueuedTask {
+                task_id,
+                run_id,
+                dag_name: self.get_dag_name(),
+                queued_date: Utc::now().into(),
+                attempt,
+            },
+            depth,
+        ))
+    }
+
+    #[timed(duration(printer = "debug!"))]
+    fn print_priority_queue(&mut self) {}
+
+    fn take_last_stdout_line(
+        &mut self,
+        run_id: usize,
+        task_id: usize,
+        attempt: usize,
+    ) -> Box<dyn Fn() -> String + Send> {
+        let storage = self.storage.clone();
+        let handle = self.handle.clone();
+        let failure = self.failure.clone();
+        Box::new(move || {
+            block_on(
+                &handle,
+                &failure,
+                storage.get_last_output(run_id, task_id, attempt),
+            )
+            .unwrap_or("null".into())
+        })
+    }
+}
+
+// a failed call keeps its error for `blocking` and returns a default, the runner then only winds
+// down as every later call returns a default without touching storage. the reads with no default
+// (`get_task_by_id`, `get_task_status`, `get_task_result`) unwind instead, so nothing is worked
+// off made up tasks
+fn block_on<T: Default>(
+    handle: &Handle,
+    failure: &Mutex<Option<StorageError>>,
+    future: impl Future<Output = StorageResult<T>>,
+) -> T {
+    if failure.lock().unwrap().is_some() {
+        return T::default();
+    }
+    match handle.block_on(future) {
+        Ok(value) => value,
+        Err(err) => {
+            failure.lock().unwrap().get_or_insert(err);
+            T::default()
+        }
+    }
+}
+
+// appends the line if it fits in the `max` bytes of the attempt's log after the `written` ones,
+// the line crossing the limit is cut and followed by a marker and the attempt is flagged, stdout
+// lines past it still become the attempt's last output
+async fn append_capped_log(
+    run_id: usize,
+    task_id: usize,
+    attempt: usize,
+    line: LogLine,
+    written: usize,
+    max: usize,
+    storage: &dyn Storage,
+) -> StorageResult<()> {
+    if written + line.line.len() <= max {
+        return storage.append_log(run_id, task_id, attempt, &line).await;
+    }
+
+    if written <= max {
+        let cut = prefix(&line.line, max - written);
+        if !cut.is_empty() {
+            let cut = LogLine {
+                line: cut.to_string(),
+                ..line.clone()
+            };
+            storage.append_log(run_id, task_id, attempt, &cut).await?;
+        }
+        let marker = LogLine::new(LogStream::Stderr, log_truncated_marker(max));
+        storage
+            .append_log(run_id, task_id, attempt, &marker)
+            .await?;
+        storage
+            .set_truncated(run_id, task_id, attempt, Truncated::Log)
+            .await?;
+    }
+    if line.stream == LogStream::Stdout {
+        let output = truncate_output(&line.line, get_max_result_bytes());
+        storage
+            .set_last_output(run_id, task_id, attempt, &output)
+            .await?;
+    }
+    Ok(())
+}
+
+// walks the graph in topological order, keeping the known depths and computing the rest as one
+// more than their deepest upstream, returns only the newly computed depths
+fn compute_missing_depths(
+    edges: &HashSet<(usize, usize)>,
+    depths: &mut HashMap<usize, usize>,
+) -> HashMap<usize, usize> {
+    let mut upstream: HashMap<usize, Vec<usize>> = HashMap::new();
+    let mut downstream: HashMap<usize, Vec<usize>> = HashMap::new();
+    let mut in_degree: HashMap<usize, usize> = HashMap::new();
+
+    for (up, down) in edges {
+        upstream.entry(*down).or_default().push(*up);
+        downstream.entry(*up).or_default().push(*down);
+        in_degree.entry(*up).or_default();
+        *in_degree.entry(*down).or_default() += 1;
+    }
+
+    let mut queue: VecDeque<usize> = in_degree
+        .iter()
+        .filter_map(|(task_id, degree)| if *degree == 0 { Some(*task_id) } else { None })
+        .collect();
+    let mut missing = HashMap::new();
+
+    while let Some(task_id) = queue.pop_front() {
+        if !depths.contains_key(&task_id) {
+            let depth = upstream
+                .get(&task_id)
+                .into_iter()
+                .flatten()
+                .map(|up| depths[up] + 1)
+                .max()
+                .unwrap_or(0);
+            depths.insert(task_id, depth);
+            missing.insert(task_id, depth);
+        }
+
+        for down in downstream.get(&task_id).into_iter().flatten() {
+            let degree = in_degree.get_mut(down).unwrap();
+            *degree -= 1;
+            if *degree == 0 {
+                queue.push_back(*down);
+            }
+        }
+    }
+
+    missing
+}
@@ -0,0 +1,336 @@
+use std::iter::successors;
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use saffron::Cron;
+use serde::{Deserialize, Serialize};
+
+// how far back get_previous_fire_times searches before giving up
+const MAX_LOOKBACK_DAYS: i64 = 366 * 10;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ScheduleError {
+    pub error: String,
+
+    // byte offset of the offending part of the schedule, if it could be determined
+    pub position: Option<usize>,
+}
+
+impl ScheduleError {
+    fn new(error: &str, position: Option<usize>) -> Self {
+        Self {
+            error: error.to_owned(),
+            position,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Schedule {
+    Cron(Cron),
+
+    // fires every `interval` starting exactly at `start_date`
+    Interval {
+        start_date: DateTime<Utc>,
+        interval: Duration,
+    },
+}
+
+impl Schedule {
+    // accepts cron expressions, presets such as `@daily` and ISO-8601 durations such as `PT15M`
+    pub fn parse(schedule: &str, start_date: Option<DateTime<Utc>>) -> Result<Self, ScheduleError> {
+        let schedule = schedule.trim();
+
+        if schedule.starts_with('@') {
+            let cron = match schedule {
+                "@yearly" | "@annually" => "0 0 1 1 *",
+                "@monthly" => "0 0 1 * *",
+                "@weekly" => "0 0 * * SUN",
+                "@daily" | "@midnight" => "0 0 * * *",
+                "@hourly" => "0 * * * *",
+                _ => {
+                    return Err(ScheduleError::new(
+                        &format!("unknown preset: {schedule}"),
+                        Some(0),
+                    ))
+                }
+            };
+            return parse_cron(cron).map(Schedule::Cron);
+        }
+
+        if schedule.starts_with('P') {
+            return Ok(Schedule::Interval {
+                start_date: start_date.unwrap_or(Utc.timestamp_opt(0, 0).unwrap()),
+                interval: parse_duration(schedule)?,
+            });
+        }
+
+        parse_cron(schedule).map(Schedule::Cron)
+    }
+
+    // every fire time at or after `from`
+    pub fn iter_from(&self, from: DateTime<Utc>) -> Box<dyn Iterator<Item = DateTime<Utc>> + Send> {
+        match self {
+            Schedule::Cron(cron) => {
+                let check = cron.clone();
+                Box::new(cron.clone().iter_from(from).take_while(move |time| {
+                    if !check.contains(*time) {
+                        println!("Failed check! Cron does not contain {}.", time);
+                        return false;
+                    }
+                    true
+                }))
+            }
+            Schedule::Interval {
+                start_date,
+                interval,
+            } => {
+                let interval = *interval;
+                let interval_ms = interval.num_milliseconds();
+                // an interval reaching past the last date has no slot after `start_date`
+                let first = if from <= *start_date {
+                    Some(*start_date)
+                } else {
+                    let elapsed_ms = (from - *start_date).num_milliseconds();
+                    elapsed_ms
+                        .checked_add(interval_ms - 1)
+                        .and_then(|ms| (ms / interval_ms).checked_mul(interval_ms))
+                        .and_then(|ms| start_date.checked_add_signed(Duration::milliseconds(ms)))
+                };
+
+                Box::new(successors(first, move |time| {
+                    time.checked_add_signed(interval)
+                }))
+            }
+        }
+    }
+}
+
+pub fn parse_cron(schedule: &str) -> Result<Cron, ScheduleError> {
+    match schedule.parse::<Cron>() {
+        Ok(cron) => {
+            if !cron.any() {
+                return Err(ScheduleError::new(
+                    "Cron will never match any given time!",
+                    None,
+                ));
+            }
+            Ok(cron)
+        }
+        Err(err) => Err(ScheduleError::new(
+            &format!("{err}: {schedule}"),
+            find_error_position(schedule),
+        )),
+    }
+}
+
+// saffron's errors carry no position, so re-parse each field on its own to locate the bad one
+fn find_error_position(schedule: &str) -> Option<usize> {
+    let fields: Vec<(usize, &str)> = schedule
+        .split_whitespace()
+        .map(|field| (field.as_ptr() as usize - schedule.as_ptr() as usize, field))
+        .collect();
+
+    if fields.len() < 5 {
+        return Some(schedule.len());
+    }
+    if fields.len() > 5 {
+        return Some(fields[5].0);
+    }
+
+    for (i, (position, field)) in fields.iter().enumerate() {
+        let mut probe = ["*"; 5];
+        probe[i] = field;
+
+        if probe.join(" ").parse::<Cron>().is_err() {
+            return Some(*position);
+        }
+    }
+
+    None
+}
+
+// fixed-length ISO-8601 durations only, e.g. `P1W`, `P1DT12H`, `PT15M`, each designator at most
+// once and in order
+fn parse_duration(schedule: &str) -> Result<Duration, ScheduleError> {
+    let mut interval = Duration::zero();
+    let mut in_time = false;
+    let mut number_start: Option<usize> = None;
+    // rank of the last designator, `W` < `D` < `H` < `M` < `S`
+    let mut last_rank: Option<usize> = None;
+
+    for (i, c) in schedule.char_indices().skip(1) {
+        match c {
+            '0'..='9' => {
+                number_start.get_or_insert(i);
+            }
+            'T' if !in_time && number_start.is_none() => in_time = true,
+            _ => {
+                let Some(start) = number_start.take() else {
+                    return Err(ScheduleError::new("expected a number", Some(i)));
+                };
+                let value = i64::from(
+                    schedule[start..i]
+                        .parse::<i32>()
+                        .map_err(|_| ScheduleError::new("number is too large", Some(start)))?,
+                );
+
+                let (rank, part) = match (in_time, c) {
+                    (false, 'W') => (0, Duration::weeks(value)),
+                    (false, 'D') => (1, Duration::days(value)),
+                    (true, 'H') => (2, Duration::hours(value)),
+                    (true, 'M') => (3, Duration::minutes(value)),
+                    (true, 'S') => (4, Duration::seconds(value)),
+                    (false, 'Y') | (false, 'M') => {
+                        return Err(ScheduleError::new(
+                            "years and months are not supported, use weeks or days",
+                            Some(i),
+                        ))
+                    }
+                    _ => {
+                        return Err(ScheduleError::new(
+                            &format!("unexpected designator: {c}"),
+                            Some(i),
+                        ))
+                    }
+                };
+                if last_rank.is_some_and(|last_rank| rank <= last_rank) {
+                    return Err(ScheduleError::new(
+                        &format!("designator {c} is repeated or out of order"),
+                        Some(i),
+                    ));
+                }
+                last_rank = Some(rank);
+
+                interval = interval
+                    .checked_add(&part)
+                    .ok_or_else(|| ScheduleError::new("interval is too large", Some(i)))?;
+            }
+        }
+    }
+
+    if let Some(start) = number_start {
+        return Err(ScheduleError::new("missing designator", Some(start)));
+    }
+    if in_time && last_rank.is_none_or(|last_rank| last_rank < 2) {
+        return Err(ScheduleError::new(
+            "expected hours, minutes or seconds after T",
+            Some(schedule.len()),
+        ));
+    }
+    if interval <= Duration::zero() {
+        return Err(ScheduleError::new(
+            "interval must be greater than zero",
+            None,
+        ));
+    }
+
+    Ok(interval)
+}
+
+pub fn get_next_fire_times(
+    schedule: &Schedule,
+    from: DateTime<Utc>,
+    end_date: Option<DateTime<Utc>>,
+    count: usize,
+) -> Vec<DateTime<Utc>> {
+    schedule
+        .iter_from(from)
+        .take_while(|time| end_date.is_none_or(|end_date| *time <= end_date))
+        .take(count)
+        .collect()
+}
+
+// schedules can only be iterated forwards, so search a doubling window before `before`
+pub fn get_previous_fire_times(
+    schedule: &Schedule,
+    before: DateTime<Utc>,
+    start_date: Option<DateTime<Utc>>,
+    count: usize,
+) -> Vec<DateTime<Utc>> {
+    if count == 0 {
+        return vec![];
+    }
+
+    let mut window = Duration::hours(1);
+
+    loop {
+        let mut from = before - window;
+        let exhausted = match start_date {
+            Some(start_date) if from <= start_date => {
+                from = start_date;
+                true
+            }
+            _ => window >= Duration::days(MAX_LOOKBACK_DAYS),
+        };
+
+        let times: Vec<DateTime<Utc>> = schedule
+            .iter_from(from)
+            .take_while(|time| *time < before)
+            .collect();
+
+        if times.len() >= count || exhausted {
+            return times[times.len().saturating_sub(count)..].to_vec();
+        }
+
+        window = window * 2;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_intervals() {
+        assert_eq!(parse_duration("P1W").unwrap(), Duration::weeks(1));
+        assert_eq!(parse_duration("PT15M").unwrap(), Duration::minutes(15));
+        assert_eq!(
+            parse_duration("P1W2DT3H4M5S").unwrap(),
+            Duration::weeks(1)
+                + Duration::days(2)
+                + Duration::hours(3)
+                + Duration::minutes(4)
+                + Duration::seconds(5)
+        );
+    }
+
+    #[test]
+    fn rejects_repeated_and_out_of_order_designators() {
+        for schedule in ["P1D1D", "PT1M1H", "P1D1W", "PT1S1S"] {
+            let err = parse_duration(schedule).unwrap_err();
+            assert_eq!(err.position, Some(schedule.len() - 1), "{schedule}");
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_intervals() {
+        for schedule in [
+            "P", "PT", "P0D", "P1Y", "P1M", "PT1D", "P1", "PW", "P1H", "P1DT",
+        ] {
+            assert!(parse_duration(schedule).is_err(), "{schedule}");
+        }
+    }
+
+    #[test]
+    fn rejects_intervals_that_would_overflow() {
+        let err = parse_duration("P2147483648W").unwrap_err();
+        assert_eq!(err.position, Some(1));
+        assert!(parse_duration(&format!("P{}", "2147483647W".repeat(10))).is_err());
+        assert!(
+            parse_duration("P2147483647W2147483647DT2147483647H2147483647M2147483647S").is_ok()
+        );
+    }
+
+    #[test]
+    fn intervals_past_the_last_date_have_no_fire_times() {
+        let schedule = Schedule::parse("P20000000W", None).unwrap();
+        let from = Utc.timestamp_opt(1, 0).unwrap();
+        assert_eq!(schedule.iter_from(from).next(), None);
+
+        let start_date = Utc.timestamp_opt(0, 0).unwrap();
+        assert_eq!(
+            schedule.iter_from(start_date).collect::<Vec<_>>(),
+            vec![start_date]
+        );
+    }
+}
@@ -1,14 +1,16 @@
-use std::{collections::HashMap, time::Duration};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
 use chrono::{DateTime, Utc};
 
-use deadpool_redis::Pool;
-use saffron::Cron;
 use tokio::time::sleep;
 
-use crate::{_get_dags, _get_hash, _trigger_run, redis_runner::RedisRunner, statics::_get_options};
+use crate::{
+    _get_dags, _get_hash, _trigger_run,
+    statics::{_get_options, _set_scheduling_error},
+    storage::Storage,
+};
 
-pub fn scheduler(up_to: &DateTime<Utc>, pool: Pool) {
+pub fn scheduler(up_to: &DateTime<Utc>, storage: Arc<dyn Storage>) {
     let up_to_initial = *up_to;
 
     tokio::spawn(async move {
@@ -17,68 +19,30 @@ pub fn scheduler(up_to: &DateTime<Utc>, pool: Pool) {
             let dags = _get_dags();
 
             for dag_name in dags {
-                let pool = pool.clone();
-
-                let options = _get_options(&dag_name);
-                let up_to = **last_checked.get(&dag_name).get_or_insert(&up_to_initial);
-
-                last_checked.insert(dag_name.clone(), Utc::now());
-
-                if let Some(schedule) = &options.schedule {
-                    match schedule.parse::<Cron>() {
-                        Ok(cron) => {
-                            if !cron.any() {
-                                println!("Cron will never match any given time!");
-                                return;
-                            }
-                            // println!("checking for schedules: {dag_name} {up_to}");
-
-                            if let Some(end_date) = options.end_date {
-                                if end_date <= up_to {
-                                    return;
-                                }
-                            }
-
-                            if let Some(start_date) = options.start_date {
-                                if start_date >= up_to {
-                                    return;
-                                }
-                            }
-
-                            let futures = cron.clone().iter_from(up_to);
-
-                            'inner: for time in futures {
-                                if !cron.contains(time) {
-                                    println!("Failed check! Cron does not contain {}.", time);
-                                    break 'inner;
-                                }
-                                if time >= Utc::now() {
-                                    break 'inner;
-                                }
-                                if let Some(end_date) = options.end_date {
-                                    if time > end_date {
-                                        break 'inner;
-                                    }
-                                }
-                                // check if date is already in db
-                                if RedisRunner::contains_logical_date(
-                                    &dag_name,
-                                    &_get_hash(&dag_name),
-                                    time,
-                                    pool.clone(),
-                                )
-                                .await
-                                {
-                                    continue 'inner;
-                                }
-
-                                _trigger_run(&dag_name, time, pool.clone()).await;
-                                println!("scheduling {} {dag_name}", time.format("%F %R"));
-                            }
-                        }
-                        Err(err) => println!("{err}: {schedule}"),
+                let up_to = *last_checked.get(&dag_name).unwrap_or(&up_to_initial);
+                let checked = Utc::now();
+
+                // each dag is scheduled in its own task so a panic only affects that dag
+                let error = match tokio::spawn(schedule_dag(
+                    dag_name.clone(),
+                    up_to,
+                    storage.clone(),
+                ))
+                .await
+                {
+                    Ok(Ok(())) => None,
+                    Ok(Err(err)) => Some(err),
+                    Err(err) => Some(format!("scheduling panicked: {err}")),
+                };
+
+                match &error {
+                    Some(err) => println!("failed to schedule {dag_name}: {err}"),
+                    // only move forward once every slot up to now was handled
+                    None => {
+                        last_checked.insert(dag_name.clone(), checked);
                     }
                 }
+                _set_scheduling_error(&dag_name, error);
             }
 
             // TODO read from env
@@ -86,3 +50,55 @@ pub fn scheduler(up_to: &DateTime<Utc>, pool: Pool) {
         }
     });
 }
+
+async fn schedule_dag(
+    dag_name: String,
+    up_to: DateTime<Utc>,
+    storage: Arc<dyn Storage>,
+) -> Result<(), String> {
+    let options = _get_options(&dag_name);
+
+    let Some(schedule) = options.get_schedule() else {
+        return Ok(());
+    };
+    let schedule = schedule.map_err(|err| err.error)?;
+
+    // println!("checking for schedules: {dag_name} {up_to}");
+
+    if let Some(end_date) = options.end_date {
+        if end_date <= up_to {
+            return Ok(());
+        }
+    }
+
+    let from = match options.start_date {
+        Some(start_date) if start_date > up_to => start_date.into(),
+        _ => up_to,
+    };
+
+    for time in schedule.iter_from(from) {
+        if time >= Utc::now() {
+            break;
+        }
+        if let Some(end_date) = options.end_date {
+            if time > end_date {
+                break;
+            }
+        }
+        // check if date is already in db
+        if storage
+            .contains_logical_date(&dag_name, &_get_hash(&dag_name), time)
+            .await
+            .map_err(|err| err.to_string())?
+        {
+            continue;
+        }
+
+        _trigger_run(&dag_name, time, storage.clone())
+            .await
+            .map_err(|err| err.to_string())?;
+        println!("scheduling {} {dag_name}", time.format("%F %R"));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,1295 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    ops::Range,
+    str::FromStr,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::{stream, StreamExt};
+use sqlx::{
+    any::{install_default_drivers, AnyPoolOptions},
+    AnyPool,
+};
+use thepipelinetool::server::*;
+
+use crate::{
+    backfill::Backfill,
+    events::{log_publish_failure, Event, EventStream},
+    storage::{
+        unfinished_statuses, LogLine, LogStream, Run, RunQuery, RunStatus, SortOrder, Storage,
+        StorageResult, Truncated, STDERR_TAIL_LINES, STDERR_TAIL_SCAN,
+    },
+};
+
+// works on both sqlite and postgres, `$n` placeholders are understood by both drivers
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS counters (
+        name TEXT PRIMARY KEY,
+        value BIGINT NOT NULL
+    )",
+    "CREATE TABLE IF NOT EXISTS runs (
+        run_id BIGINT PRIMARY KEY,
+        dag_name TEXT NOT NULL,
+        dag_hash TEXT NOT NULL,
+        logical_date TEXT NOT NULL,
+        run TEXT NOT NULL
+    )",
+    "CREATE INDEX IF NOT EXISTS runs_by_dag ON runs (dag_name, run_id)",
+    "CREATE INDEX IF NOT EXISTS runs_by_logical_date ON runs (dag_name, dag_hash, logical_date)",
+    "CREATE INDEX IF NOT EXISTS runs_by_date ON runs (dag_name, logical_date)",
+    // kept in step with the statuses of the run's tasks, see `update_run_status`
+    "CREATE TABLE IF NOT EXISTS run_statuses (
+        run_id BIGINT PRIMARY KEY,
+        dag_name TEXT NOT NULL,
+        status TEXT NOT NULL
+    )",
+    "CREATE INDEX IF NOT EXISTS run_statuses_by_dag ON run_statuses (dag_name, status, run_id)",
+    // outlives purged runs so their slots aren't scheduled again
+    "CREATE TABLE IF NOT EXISTS logical_dates (
+        dag_name TEXT NOT NULL,
+        dag_hash TEXT NOT NULL,
+        logical_date TEXT NOT NULL,
+        PRIMARY KEY (dag_name, dag_hash, logical_date)
+    )",
+    // the `WHERE true` stops sqlite from parsing `ON CONFLICT` as part of the select
+    "INSERT INTO logical_dates (dag_name, dag_hash, logical_date)
+    SELECT dag_name, dag_hash, logical_date FROM runs WHERE true
+    ON CONFLICT DO NOTHING",
+    "CREATE TABLE IF NOT EXISTS tasks (
+        run_id BIGINT NOT NULL,
+        task_id BIGINT NOT NULL,
+        task TEXT NOT NULL,
+        status TEXT NOT NULL,
+        depth BIGINT,
+        PRIMARY KEY (run_id, task_id)
+    )",
+    "CREATE INDEX IF NOT EXISTS tasks_by_status ON tasks (run_id, status)",
+    "CREATE TABLE IF NOT EXISTS task_results (
+        run_id BIGINT NOT NULL,
+        task_id BIGINT NOT NULL,
+        seq BIGINT NOT NULL,
+        result TEXT NOT NULL,
+        PRIMARY KEY (run_id, task_id, seq)
+    )",
+    "CREATE TABLE IF NOT EXISTS dependency_keys (
+        run_id BIGINT NOT NULL,
+        task_id BIGINT NOT NULL,
+        upstream_id BIGINT NOT NULL,
+        upstream_key TEXT NOT NULL,
+        value TEXT NOT NULL,
+        PRIMARY KEY (run_id, task_id, upstream_id, upstream_key, value)
+    )",
+    "CREATE TABLE IF NOT EXISTS logs (
+        run_id BIGINT NOT NULL,
+        task_id BIGINT NOT NULL,
+        attempt BIGINT NOT NULL,
+        line_no BIGINT NOT NULL,
+        line TEXT NOT NULL,
+        PRIMARY KEY (run_id, task_id, attempt, line_no)
+    )",
+    // the last line of each attempt, see `Storage::get_last_output`
+    "CREATE TABLE IF NOT EXISTS outputs (
+        run_id BIGINT NOT NULL,
+        task_id BIGINT NOT NULL,
+        attempt BIGINT NOT NULL,
+        line TEXT NOT NULL,
+        PRIMARY KEY (run_id, task_id, attempt)
+    )",
+    // the parts of each attempt cut short by the size limits
+    "CREATE TABLE IF NOT EXISTS truncations (
+        run_id BIGINT NOT NULL,
+        task_id BIGINT NOT NULL,
+        attempt BIGINT NOT NULL,
+        part TEXT NOT NULL,
+        PRIMARY KEY (run_id, task_id, attempt, part)
+    )",
+    "CREATE TABLE IF NOT EXISTS edges (
+        run_id BIGINT NOT NULL,
+        upstream_id BIGINT NOT NULL,
+        downstream_id BIGINT NOT NULL,
+        PRIMARY KEY (run_id, upstream_id, downstream_id)
+    )",
+    "CREATE INDEX IF NOT EXISTS edges_by_downstream ON edges (run_id, downstream_id)",
+    "CREATE TABLE IF NOT EXISTS queue (
+        queued_task TEXT PRIMARY KEY,
+        score BIGINT NOT NULL
+    )",
+    "CREATE INDEX IF NOT EXISTS queue_by_score ON queue (score, queued_task)",
+    "CREATE TABLE IF NOT EXISTS temp_queue (
+        queued_task TEXT PRIMARY KEY
+    )",
+    "CREATE TABLE IF NOT EXISTS backfills (
+        backfill_id BIGINT PRIMARY KEY,
+        dag_name TEXT NOT NULL,
+        backfill TEXT NOT NULL
+    )",
+    "CREATE TABLE IF NOT EXISTS events (
+        seq BIGINT PRIMARY KEY,
+        event TEXT NOT NULL
+    )",
+];
+
+// subscribers poll far more often than this many events get published
+const EVENTS_KEPT: usize = 10_000;
+// seqs behind the newest one a subscriber has seen that it polls again
+const EVENTS_REREAD: i64 = 100;
+
+// the status of the run `{table}.run_id` derived from its tasks, binds the unfinished task
+// statuses to $1-$3 and the failed one to $4
+fn run_status_case(table: &str) -> String {
+    format!(
+        "CASE
+            WHEN (SELECT COUNT(*) FROM tasks
+                WHERE tasks.run_id = {table}.run_id AND tasks.status IN ($1, $2, $3)) > 0
+            THEN '{running}'
+            WHEN (SELECT COUNT(*) FROM tasks
+                WHERE tasks.run_id = {table}.run_id AND tasks.status = $4) > 0
+            THEN '{failed}'
+            ELSE '{success}'
+        END",
+        running = RunStatus::Running.as_str(),
+        failed = RunStatus::Failed.as_str(),
+        success = RunStatus::Success.as_str(),
+    )
+}
+
+fn bind_run_status_case<'q>(
+    query: sqlx::query::Query<'q, sqlx::Any, sqlx::any::AnyArguments<'q>>,
+) -> sqlx::query::Query<'q, sqlx::Any, sqlx::any::AnyArguments<'q>> {
+    let [pending, running, retrying] = unfinished_statuses();
+    query
+        .bind(pending)
+        .bind(running)
+        .bind(retrying)
+        .bind(TaskStatus::Failure.as_str())
+}
+
+// takes `count` values of the named counter, which starts at 1, inside a transaction the
+// counter's row stays locked until it ends
+async fn next_counter_values<'e>(
+    executor: impl sqlx::Executor<'e, Database = sqlx::Any>,
+    name: &str,
+    count: usize,
+) -> StorageResult<Range<usize>> {
+    let end = sqlx::query_scalar::<_, i64>(
+        "INSERT INTO counters (name, value) VALUES ($1, $2)
+        ON CONFLICT (name) DO UPDATE SET value = counters.value + $2
+        RETURNING value",
+    )
+    .bind(name)
+    .bind(count as i64)
+    .fetch_one(executor)
+    .await? as usize;
+    Ok(end + 1 - count..end + 1)
+}
+
+pub struct SqlStorage {
+    pool: AnyPool,
+    // postgres can skip rows locked by other transactions, sqlite has a single writer anyway
+    postgres: bool,
+}
+
+impl SqlStorage {
+    pub async fn connect(database_url: &str) -> Self {
+        install_default_drivers();
+
+        // sqlite only allows a single writer
+        let max_connections = if database_url.starts_with("sqlite:") {
+            1
+        } else {
+            10
+        };
+
+        let pool = AnyPoolOptions::new()
+            .max_connections(max_connections)
+            .connect(database_url)
+            .await
+            .unwrap();
+
+        for migration in MIGRATIONS {
+            sqlx::query(migration).execute(&pool).await.unwrap();
+        }
+
+        // logs and results numbered before they had counters
+        for backfill in [
+            "INSERT INTO counters (name, value)
+            SELECT 'l:' || CAST(run_id AS TEXT) || ':' || CAST(task_id AS TEXT) || ':'
+                || CAST(attempt AS TEXT), MAX(line_no) + 1
+            FROM logs GROUP BY run_id, task_id, attempt
+            ON CONFLICT DO NOTHING",
+            "INSERT INTO counters (name, value)
+            SELECT 'r:' || CAST(run_id AS TEXT) || ':' || CAST(task_id AS TEXT), MAX(seq) + 1
+            FROM task_results GROUP BY run_id, task_id
+            ON CONFLICT DO NOTHING",
+        ] {
+            sqlx::query(backfill).execute(&pool).await.unwrap();
+        }
+
+        // runs created before `run_statuses` existed
+        bind_run_status_case(sqlx::query(&format!(
+            "INSERT INTO run_statuses (run_id, dag_name, status)
+            SELECT run_id, dag_name, {} FROM runs WHERE true
+            ON CONFLICT DO NOTHING",
+            run_status_case("runs")
+        )))
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        Self {
+            pool,
+            postgres: database_url.starts_with("postgres"),
+        }
+    }
+
+    async fn next_value(&self, name: &str) -> StorageResult<usize> {
+        Ok(self.next_values(name, 1).await?.start)
+    }
+
+    async fn next_values(&self, name: &str, count: usize) -> StorageResult<Range<usize>> {
+        next_counter_values(&self.pool, name, count).await
+    }
+
+    // the result event of the attempt, with the end of its stderr when it failed
+    async fn publish_task_result(&self, run_id: usize, result: &TaskResult) -> StorageResult<()> {
+        let dag_name =
+            sqlx::query_scalar::<_, String>("SELECT dag_name FROM runs WHERE run_id = $1")
+                .bind(run_id as i64)
+                .fetch_optional(&self.pool)
+                .await?;
+        if let Some(dag_name) = dag_name {
+            let stderr_tail = match result.success {
+                true => vec![],
+                false => {
+                    self.get_stderr_tail(
+                        run_id,
+                        result.task_id,
+                        result.attempt,
+                        STDERR_TAIL_LINES,
+                        STDERR_TAIL_SCAN,
+                    )
+                    .await?
+                }
+            };
+            self.publish_event(&Event::TaskResult {
+                dag_name,
+                run_id,
+                task_id: result.task_id,
+                attempt: result.attempt,
+                success: result.success,
+                stderr_tail,
+                truncated: self
+                    .get_truncated(run_id, result.task_id, result.attempt)
+                    .await?,
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn update_run_status(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Any>,
+        run_id: usize,
+    ) -> StorageResult<()> {
+        bind_run_status_case(sqlx::query(&format!(
+            "UPDATE run_statuses SET status = {} WHERE run_id = $5",
+            run_status_case("run_statuses")
+        )))
+        .bind(run_id as i64)
+        .execute(&mut **tx)
+        .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for SqlStorage {
+    async fn create_new_run(
+        &self,
+        dag_name: &str,
+        dag_hash: &str,
+        logical_date: DateTime<Utc>,
+        data_interval: (DateTime<Utc>, DateTime<Utc>),
+    ) -> StorageResult<usize> {
+        let run_id = self.next_value("run").await?;
+        let run = serde_json::to_string(&Run {
+            run_id,
+            date: logical_date,
+            data_interval_start: Some(data_interval.0),
+            data_interval_end: Some(data_interval.1),
+        })
+        .unwrap();
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "INSERT INTO runs (run_id, dag_name, dag_hash, logical_date, run)
+            VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(run_id as i64)
+        .bind(dag_name)
+        .bind(dag_hash)
+        .bind(logical_date.to_rfc3339())
+        .bind(run)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            "INSERT INTO logical_dates (dag_name, dag_hash, logical_date) VALUES ($1, $2, $3)
+            ON CONFLICT DO NOTHING",
+        )
+        .bind(dag_name)
+        .bind(dag_hash)
+        .bind(logical_date.to_rfc3339())
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("INSERT INTO run_statuses (run_id, dag_name, status) VALUES ($1, $2, $3)")
+            .bind(run_id as i64)
+            .bind(dag_name)
+            .bind(RunStatus::Running.as_str())
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        log_publish_failure(
+            self.publish_event(&Event::RunCreated {
+                dag_name: dag_name.to_string(),
+                run_id,
+                logical_date,
+            })
+            .await,
+        );
+        Ok(run_id)
+    }
+
+    async fn get_run(&self, run_id: usize) -> StorageResult<Option<Run>> {
+        Ok(
+            sqlx::query_scalar::<_, String>("SELECT run FROM runs WHERE run_id = $1")
+                .bind(run_id as i64)
+                .fetch_optional(&self.pool)
+                .await?
+                .map(|run| serde_json::from_str(&run).unwrap()),
+        )
+    }
+
+    async fn get_runs(&self, dag_name: &str) -> StorageResult<Vec<Run>> {
+        Ok(sqlx::query_scalar::<_, String>(
+            "SELECT run FROM runs WHERE dag_name = $1 ORDER BY run_id",
+        )
+        .bind(dag_name)
+        .fetch_all(&self.pool)
+        .await?
+        .iter()
+        .map(|run| serde_json::from_str(run).unwrap())
+        .collect())
+    }
+
+    async fn get_last_run(&self, dag_name: &str) -> StorageResult<Option<Run>> {
+        Ok(sqlx::query_scalar::<_, String>(
+            "SELECT run FROM runs WHERE dag_name = $1 ORDER BY run_id DESC LIMIT 1",
+        )
+        .bind(dag_name)
+        .fetch_optional(&self.pool)
+        .await?
+        .map(|run| serde_json::from_str(&run).unwrap()))
+    }
+
+    async fn get_recent_runs(&self, dag_name: &str) -> StorageResult<Vec<Run>> {
+        Ok(sqlx::query_scalar::<_, String>(
+            "SELECT run FROM (
+                SELECT run, run_id FROM runs WHERE dag_name = $1 ORDER BY run_id DESC LIMIT 10
+            ) AS recent ORDER BY run_id",
+        )
+        .bind(dag_name)
+        .fetch_all(&self.pool)
+        .await?
+        .iter()
+        .map(|run| serde_json::from_str(run).unwrap())
+        .collect())
+    }
+
+    async fn list_runs(
+        &self,
+        dag_name: &str,
+        query: &RunQuery,
+        limit: usize,
+    ) -> StorageResult<Vec<(Run, RunStatus)>> {
+        // only the given filters are added so every one of them can use an index
+        let mut sql = "SELECT runs.run, run_statuses.status FROM runs
+            JOIN run_statuses ON run_statuses.run_id = runs.run_id
+            WHERE runs.dag_name = $1"
+            .to_string();
+        let mut binds = vec![];
+
+        if let Some(status) = query.status {
+            binds.push(status.as_str().to_string());
+            sql += &format!(" AND run_statuses.status = ${}", binds.len() + 1);
+        }
+        if let Some(from) = query.from {
+            binds.push(from.to_rfc3339());
+            sql += &format!(" AND runs.logical_date >= ${}", binds.len() + 1);
+        }
+        if let Some(to) = query.to {
+            binds.push(to.to_rfc3339());
+            sql += &format!(" AND runs.logical_date <= ${}", binds.len() + 1);
+        }
+        let (cmp, order) = match query.order {
+            SortOrder::Asc => (">", "ASC"),
+            SortOrder::Desc => ("<", "DESC"),
+        };
+        let by_date = query.from.is_some() || query.to.is_some();
+        // within a date range the cursor only applies along with its run's date
+        let cursor = query
+            .cursor
+            .filter(|_| !by_date || query.cursor_date.is_some());
+        if by_date {
+            if let Some(cursor_date) = cursor.and(query.cursor_date) {
+                binds.push(cursor_date.to_rfc3339());
+                let date = binds.len() + 1;
+                sql += &format!(
+                    " AND (runs.logical_date {cmp} ${date}
+                    OR (runs.logical_date = ${date} AND runs.run_id {cmp} ${}))",
+                    binds.len() + 2
+                );
+            }
+            sql +=
+                &format!(" ORDER BY runs.logical_date {order}, runs.run_id {order} LIMIT {limit}");
+        } else {
+            if cursor.is_some() {
+                sql += &format!(" AND runs.run_id {cmp} ${}", binds.len() + 2);
+            }
+            sql += &format!(" ORDER BY runs.run_id {order} LIMIT {limit}");
+        }
+
+        let mut rows = sqlx::query_as::<_, (String, String)>(&sql).bind(dag_name);
+        for bind in binds {
+            rows = rows.bind(bind);
+        }
+        if let Some(cursor) = cursor {
+            rows = rows.bind(cursor as i64);
+        }
+
+        Ok(rows
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(|(run, status)| {
+                (
+                    serde_json::from_str(run).unwrap(),
+                    RunStatus::from_str(status).unwrap(),
+                )
+            })
+            .collect())
+    }
+
+    async fn get_run_status(&self, run_id: usize) -> StorageResult<Option<RunStatus>> {
+        Ok(
+            sqlx::query_scalar::<_, String>("SELECT status FROM run_statuses WHERE run_id = $1")
+                .bind(run_id as i64)
+                .fetch_optional(&self.pool)
+                .await?
+                .map(|status| RunStatus::from_str(&status).unwrap()),
+        )
+    }
+
+    async fn is_run_of_dag(&self, dag_name: &str, run_id: usize) -> StorageResult<bool> {
+        Ok(sqlx::query_scalar::<_, i64>(
+            "SELECT COUNT(*) FROM runs WHERE run_id = $1 AND dag_name = $2",
+        )
+        .bind(run_id as i64)
+        .bind(dag_name)
+        .fetch_one(&self.pool)
+        .await?
+            > 0)
+    }
+
+    async fn get_expired_run_ids(
+        &self,
+        dag_name: &str,
+        older_than: Option<DateTime<Utc>>,
+        keep: Option<usize>,
+    ) -> StorageResult<Vec<usize>> {
+        let mut conditions = vec![];
+        if older_than.is_some() {
+            conditions.push("logical_date < $2".to_string());
+        }
+        // everything up to the run just past the newest `keep`, none when there are fewer
+        if let Some(keep) = keep {
+            conditions.push(format!(
+                "run_id <= (
+                    SELECT run_id FROM runs WHERE dag_name = $1
+                    ORDER BY run_id DESC LIMIT 1 OFFSET {keep}
+                )"
+            ));
+        }
+        if conditions.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let sql = format!(
+            "SELECT run_id FROM runs WHERE dag_name = $1 AND ({}) ORDER BY run_id",
+            conditions.join(" OR ")
+        );
+        let mut run_ids = sqlx::query_scalar::<_, i64>(&sql).bind(dag_name);
+        if let Some(older_than) = older_than {
+            run_ids = run_ids.bind(older_than.to_rfc3339());
+        }
+
+        Ok(run_ids
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .map(|run_id| run_id as usize)
+            .collect())
+    }
+
+    async fn contains_logical_date(
+        &self,
+        dag_name: &str,
+        dag_hash: &str,
+        logical_date: DateTime<Utc>,
+    ) -> StorageResult<bool> {
+        Ok(sqlx::query_scalar::<_, i64>(
+            "SELECT COUNT(*) FROM logical_dates
+            WHERE dag_name = $1 AND dag_hash = $2 AND logical_date = $3",
+        )
+        .bind(dag_name)
+        .bind(dag_hash)
+        .bind(logical_date.to_rfc3339())
+        .fetch_one(&self.pool)
+        .await?
+            > 0)
+    }
+
+    async fn delete_run(&self, _dag_name: &str, run_id: usize) -> StorageResult<()> {
+        let mut tx = self.pool.begin().await?;
+        for table in [
+            "runs",
+            "run_statuses",
+            "tasks",
+            "task_results",
+            "dependency_keys",
+            "logs",
+            "outputs",
+            "truncations",
+            "edges",
+        ] {
+            sqlx::query(&format!("DELETE FROM {table} WHERE run_id = $1"))
+                .bind(run_id as i64)
+                .execute(&mut *tx)
+                .await?;
+        }
+        sqlx::query(
+            "DELETE FROM counters WHERE name = $1 OR name LIKE $2 OR name LIKE $3 OR name LIKE $4",
+        )
+        .bind(format!("ti:{run_id}"))
+        .bind(format!("a:{run_id}:%"))
+        .bind(format!("l:{run_id}:%"))
+        .bind(format!("r:{run_id}:%"))
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get_next_task_ids(&self, run_id: usize, count: usize) -> StorageResult<Range<usize>> {
+        let ids = self.next_values(&format!("ti:{run_id}"), count).await?;
+        Ok(ids.start - 1..ids.end - 1)
+    }
+
+    async fn insert_tasks(&self, run_id: usize, tasks: &[Task]) -> StorageResult<()> {
+        let mut tx = self.pool.begin().await?;
+        for task in tasks {
+            sqlx::query(
+                "INSERT INTO tasks (run_id, task_id, task, status) VALUES ($1, $2, $3, $4)",
+            )
+            .bind(run_id as i64)
+            .bind(task.id as i64)
+            .bind(serde_json::to_string(task).unwrap())
+            .bind(TaskStatus::Pending.as_str())
+            .execute(&mut *tx)
+            .await?;
+        }
+        self.update_run_status(&mut tx, run_id).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get_all_tasks(&self, run_id: usize) -> StorageResult<Vec<Task>> {
+        Ok(sqlx::query_scalar::<_, String>(
+            "SELECT task FROM tasks WHERE run_id = $1 ORDER BY task_id",
+        )
+        .bind(run_id as i64)
+        .fetch_all(&self.pool)
+        .await?
+        .iter()
+        .map(|task| serde_json::from_str(task).unwrap())
+        .collect())
+    }
+
+    async fn get_task_by_id(&self, run_id: usize, task_id: usize) -> StorageResult<Option<Task>> {
+        Ok(sqlx::query_scalar::<_, String>(
+            "SELECT task FROM tasks WHERE run_id = $1 AND task_id = $2",
+        )
+        .bind(run_id as i64)
+        .bind(task_id as i64)
+        .fetch_optional(&self.pool)
+        .await?
+        .map(|task| serde_json::from_str(&task).unwrap()))
+    }
+
+    async fn set_template_args(
+        &self,
+        run_id: usize,
+        task_id: usize,
+        template_args: &Value,
+    ) -> StorageResult<()> {
+        let Some(mut task) = self.get_task_by_id(run_id, task_id).await? else {
+            return Ok(());
+        };
+        task.template_args = template_args.clone();
+
+        sqlx::query("UPDATE tasks SET task = $3 WHERE run_id = $1 AND task_id = $2")
+            .bind(run_id as i64)
+            .bind(task_id as i64)
+            .bind(serde_json::to_string(&task).unwrap())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_task_depth(&self, run_id: usize, task_id: usize) -> StorageResult<Option<usize>> {
+        Ok(sqlx::query_scalar::<_, i64>(
+            "SELECT depth FROM tasks WHERE run_id = $1 AND task_id = $2 AND depth IS NOT NULL",
+        )
+        .bind(run_id as i64)
+        .bind(task_id as i64)
+        .fetch_optional(&self.pool)
+        .await?
+        .map(|depth| depth as usize))
+    }
+
+    async fn get_task_depths(&self, run_id: usize) -> StorageResult<HashMap<usize, usize>> {
+        Ok(sqlx::query_as::<_, (i64, i64)>(
+            "SELECT task_id, depth FROM tasks WHERE run_id = $1 AND depth IS NOT NULL",
+        )
+        .bind(run_id as i64)
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|(task_id, depth)| (task_id as usize, depth as usize))
+        .collect())
+    }
+
+    async fn set_task_depths(
+        &self,
+        run_id: usize,
+        depths: &HashMap<usize, usize>,
+    ) -> StorageResult<()> {
+        let mut tx = self.pool.begin().await?;
+        for (task_id, depth) in depths {
+            sqlx::query("UPDATE tasks SET depth = $3 WHERE run_id = $1 AND task_id = $2")
+                .bind(run_id as i64)
+                .bind(*task_id as i64)
+                .bind(*depth as i64)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn delete_task_depth(&self, run_id: usize, task_id: usize) -> StorageResult<()> {
+        sqlx::query("UPDATE tasks SET depth = NULL WHERE run_id = $1 AND task_id = $2")
+            .bind(run_id as i64)
+            .bind(task_id as i64)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_task_status(
+        &self,
+        run_id: usize,
+        task_id: usize,
+    ) -> StorageResult<Option<TaskStatus>> {
+        Ok(sqlx::query_scalar::<_, String>(
+            "SELECT status FROM tasks WHERE run_id = $1 AND task_id = $2",
+        )
+        .bind(run_id as i64)
+        .bind(task_id as i64)
+        .fetch_optional(&self.pool)
+        .await?
+        .map(|status| TaskStatus::from_str(&status).unwrap()))
+    }
+
+    async fn set_task_status(
+        &self,
+        run_id: usize,
+        task_id: usize,
+        task_status: TaskStatus,
+    ) -> StorageResult<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("UPDATE tasks SET status = $3 WHERE run_id = $1 AND task_id = $2")
+            .bind(run_id as i64)
+            .bind(task_id as i64)
+            .bind(task_status.as_str())
+            .execute(&mut *tx)
+            .await?;
+        self.update_run_status(&mut tx, run_id).await?;
+        let run = sqlx::query_as::<_, (String, String)>(
+            "SELECT dag_name, status FROM run_statuses WHERE run_id = $1",
+        )
+        .bind(run_id as i64)
+        .fetch_optional(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        if let Some((dag_name, run_status)) = run {
+            log_publish_failure(
+                self.publish_event(&Event::TaskStatus {
+                    dag_name,
+                    run_id,
+                    task_id,
+                    status: task_status.as_str().to_string(),
+                    run_status: RunStatus::from_str(&run_status).unwrap(),
+                })
+                .await,
+            );
+        }
+        Ok(())
+    }
+
+    async fn increment_attempt(&self, run_id: usize, task_id: usize) -> StorageResult<usize> {
+        self.next_value(&format!("a:{run_id}:{task_id}")).await
+    }
+
+    async fn get_attempt(&self, run_id: usize, task_id: usize) -> StorageResult<usize> {
+        Ok(
+            sqlx::query_scalar::<_, i64>("SELECT value FROM counters WHERE name = $1")
+                .bind(format!("a:{run_id}:{task_id}"))
+                .fetch_optional(&self.pool)
+                .await?
+                .unwrap_or(0) as usize,
+        )
+    }
+
+    async fn insert_task_results(&self, run_id: usize, result: &TaskResult) -> StorageResult<()> {
+        // numbered in the transaction so concurrent inserts are ordered by the counter's row lock
+        let mut tx = self.pool.begin().await?;
+        let seq =
+            next_counter_values(&mut *tx, &format!("r:{run_id}:{}", result.task_id), 1).await?;
+        sqlx::query(
+            "INSERT INTO task_results (run_id, task_id, seq, result) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(run_id as i64)
+        .bind(result.task_id as i64)
+        .bind(seq.start as i64 - 1)
+        .bind(serde_json::to_string(result).unwrap())
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        log_publish_failure(self.publish_task_result(run_id, result).await);
+        Ok(())
+    }
+
+    async fn get_task_result(
+        &self,
+        run_id: usize,
+        task_id: usize,
+    ) -> StorageResult<Option<TaskResult>> {
+        Ok(sqlx::query_scalar::<_, String>(
+            "SELECT result FROM task_results WHERE run_id = $1 AND task_id = $2
+            ORDER BY seq DESC LIMIT 1",
+        )
+        .bind(run_id as i64)
+        .bind(task_id as i64)
+        .fetch_optional(&self.pool)
+        .await?
+        .map(|result| serde_json::from_str(&result).unwrap()))
+    }
+
+    async fn get_all_results(
+        &self,
+        run_id: usize,
+        task_id: usize,
+    ) -> StorageResult<Vec<TaskResult>> {
+        Ok(sqlx::query_scalar::<_, String>(
+            "SELECT result FROM task_results WHERE run_id = $1 AND task_id = $2 ORDER BY seq",
+        )
+        .bind(run_id as i64)
+        .bind(task_id as i64)
+        .fetch_all(&self.pool)
+        .await?
+        .iter()
+        .map(|result| serde_json::from_str(result).unwrap())
+        .collect())
+    }
+
+    async fn get_dependency_keys(
+        &self,
+        run_id: usize,
+        task_id: usize,
+    ) -> StorageResult<HashMap<(usize, String), String>> {
+        Ok(sqlx::query_as::<_, (i64, String, String)>(
+            "SELECT upstream_id, upstream_key, value FROM dependency_keys
+            WHERE run_id = $1 AND task_id = $2",
+        )
+        .bind(run_id as i64)
+        .bind(task_id as i64)
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|(upstream_id, upstream_key, value)| ((upstream_id as usize, upstream_key), value))
+        .collect())
+    }
+
+    async fn set_dependency_keys(
+        &self,
+        run_id: usize,
+        task_id: usize,
+        upstream: (usize, String),
+        v: String,
+    ) -> StorageResult<()> {
+        sqlx::query(
+            "INSERT INTO dependency_keys (run_id, task_id, upstream_id, upstream_key, value)
+            VALUES ($1, $2, $3, $4, $5) ON CONFLICT DO NOTHING",
+        )
+        .bind(run_id as i64)
+        .bind(task_id as i64)
+        .bind(upstream.0 as i64)
+        .bind(upstream.1)
+        .bind(v)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_log_line_count(
+        &self,
+        run_id: usize,
+        task_id: usize,
+        attempt: usize,
+    ) -> StorageResult<usize> {
+        Ok(sqlx::query_scalar::<_, i64>(
+            "SELECT COUNT(*) FROM logs WHERE run_id = $1 AND task_id = $2 AND attempt = $3",
+        )
+        .bind(run_id as i64)
+        .bind(task_id as i64)
+        .bind(attempt as i64)
+        .fetch_one(&self.pool)
+        .await? as usize)
+    }
+
+    async fn get_log_lines(
+        &self,
+        run_id: usize,
+        task_id: usize,
+        attempt: usize,
+        offset: usize,
+        limit: usize,
+    ) -> StorageResult<Vec<LogLine>> {
+        Ok(sqlx::query_scalar::<_, String>(
+            "SELECT line FROM logs WHERE run_id = $1 AND task_id = $2 AND attempt = $3
+            AND line_no >= $4 ORDER BY line_no LIMIT $5",
+        )
+        .bind(run_id as i64)
+        .bind(task_id as i64)
+        .bind(attempt as i64)
+        .bind(offset as i64)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?
+        .iter()
+        .map(|entry| LogLine::parse(entry))
+        .collect())
+    }
+
+    async fn append_log(
+        &self,
+        run_id: usize,
+        task_id: usize,
+        attempt: usize,
+        line: &LogLine,
+    ) -> StorageResult<()> {
+        // stdout and stderr append concurrently, the counter's row lock orders them and a failed
+        // append gives its number back so line numbers stay dense
+        let mut tx = self.pool.begin().await?;
+        let line_no =
+            next_counter_values(&mut *tx, &format!("l:{run_id}:{task_id}:{attempt}"), 1).await?;
+        sqlx::query(
+            "INSERT INTO logs (run_id, task_id, attempt, line_no, line) VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(run_id as i64)
+        .bind(task_id as i64)
+        .bind(attempt as i64)
+        .bind(line_no.start as i64 - 1)
+        .bind(line.to_entry())
+        .execute(&mut *tx)
+        .await?;
+        if line.stream == LogStream::Stdout {
+            sqlx::query(
+                "INSERT INTO outputs (run_id, task_id, attempt, line) VALUES ($1, $2, $3, $4)
+                ON CONFLICT (run_id, task_id, attempt) DO UPDATE SET line = $4",
+            )
+            .bind(run_id as i64)
+            .bind(task_id as i64)
+            .bind(attempt as i64)
+            .bind(&line.line)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get_last_output(
+        &self,
+        run_id: usize,
+        task_id: usize,
+        attempt: usize,
+    ) -> StorageResult<Option<String>> {
+        let output = sqlx::query_scalar::<_, String>(
+            "SELECT line FROM outputs WHERE run_id = $1 AND task_id = $2 AND attempt = $3",
+        )
+        .bind(run_id as i64)
+        .bind(task_id as i64)
+        .bind(attempt as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+        if output.is_some() {
+            return Ok(output);
+        }
+
+        // attempts started before outputs were kept only have plain lines in their log
+        Ok(sqlx::query_scalar::<_, String>(
+            "SELECT line FROM logs WHERE run_id = $1 AND task_id = $2 AND attempt = $3
+            ORDER BY line_no DESC LIMIT 1",
+        )
+        .bind(run_id as i64)
+        .bind(task_id as i64)
+        .bind(attempt as i64)
+        .fetch_optional(&self.pool)
+        .await?
+        .map(|entry| LogLine::parse(&entry))
+        .filter(|line| line.timestamp.is_none())
+        .map(|line| line.line))
+    }
+
+    async fn set_last_output(
+        &self,
+        run_id: usize,
+        task_id: usize,
+        attempt: usize,
+        line: &str,
+    ) -> StorageResult<()> {
+        sqlx::query(
+            "INSERT INTO outputs (run_id, task_id, attempt, line) VALUES ($1, $2, $3, $4)
+            ON CONFLICT (run_id, task_id, attempt) DO UPDATE SET line = $4",
+        )
+        .bind(run_id as i64)
+        .bind(task_id as i64)
+        .bind(attempt as i64)
+        .bind(line)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn set_truncated(
+        &self,
+        run_id: usize,
+        task_id: usize,
+        attempt: usize,
+        truncated: Truncated,
+    ) -> StorageResult<()> {
+        sqlx::query(
+            "INSERT INTO truncations (run_id, task_id, attempt, part) VALUES ($1, $2, $3, $4)
+            ON CONFLICT DO NOTHING",
+        )
+        .bind(run_id as i64)
+        .bind(task_id as i64)
+        .bind(attempt as i64)
+        .bind(truncated.as_str())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_truncated(
+        &self,
+        run_id: usize,
+        task_id: usize,
+        attempt: usize,
+    ) -> StorageResult<Vec<Truncated>> {
+        Ok(sqlx::query_scalar::<_, String>(
+            "SELECT part FROM truncations WHERE run_id = $1 AND task_id = $2 AND attempt = $3",
+        )
+        .bind(run_id as i64)
+        .bind(task_id as i64)
+        .bind(attempt as i64)
+        .fetch_all(&self.pool)
+        .await?
+        .iter()
+        .filter_map(|truncated| Truncated::from_str(truncated).ok())
+        .collect())
+    }
+
+    async fn get_edges(&self, run_id: usize) -> StorageResult<HashSet<(usize, usize)>> {
+        Ok(sqlx::query_as::<_, (i64, i64)>(
+            "SELECT upstream_id, downstream_id FROM edges WHERE run_id = $1",
+        )
+        .bind(run_id as i64)
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|(up, down)| (up as usize, down as usize))
+        .collect())
+    }
+
+    async fn get_downstream(&self, run_id: usize, task_id: usize) -> StorageResult<Vec<usize>> {
+        Ok(sqlx::query_scalar::<_, i64>(
+            "SELECT downstream_id FROM edges WHERE run_id = $1 AND upstream_id = $2",
+        )
+        .bind(run_id as i64)
+        .bind(task_id as i64)
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|down| down as usize)
+        .collect())
+    }
+
+    async fn get_upstream(&self, run_id: usize, task_id: usize) -> StorageResult<Vec<usize>> {
+        Ok(sqlx::query_scalar::<_, i64>(
+            "SELECT upstream_id FROM edges WHERE run_id = $1 AND downstream_id = $2",
+        )
+        .bind(run_id as i64)
+        .bind(task_id as i64)
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|up| up as usize)
+        .collect())
+    }
+
+    async fn insert_edge(&self, run_id: usize, edge: (usize, usize)) -> StorageResult<()> {
+        sqlx::query(
+            "INSERT INTO edges (run_id, upstream_id, downstream_id) VALUES ($1, $2, $3)
+            ON CONFLICT DO NOTHING",
+        )
+        .bind(run_id as i64)
+        .bind(edge.0 as i64)
+        .bind(edge.1 as i64)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn remove_edge(&self, run_id: usize, edge: (usize, usize)) -> StorageResult<()> {
+        sqlx::query(
+            "DELETE FROM edges WHERE run_id = $1 AND upstream_id = $2 AND downstream_id = $3",
+        )
+        .bind(run_id as i64)
+        .bind(edge.0 as i64)
+        .bind(edge.1 as i64)
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            "DELETE FROM dependency_keys WHERE run_id = $1 AND task_id = $2 AND upstream_id = $3
+            AND upstream_key = '' AND value = ''",
+        )
+        .bind(run_id as i64)
+        .bind(edge.1 as i64)
+        .bind(edge.0 as i64)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn enqueue_task(&self, queued_task: &QueuedTask, depth: usize) -> StorageResult<()> {
+        sqlx::query(
+            "INSERT INTO queue (queued_task, score) VALUES ($1, $2)
+            ON CONFLICT (queued_task) DO UPDATE SET score = excluded.score",
+        )
+        .bind(serde_json::to_string(queued_task).unwrap())
+        .bind(depth as i64)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn pop_priority_queue(
+        &self,
+        max_threads: usize,
+    ) -> StorageResult<Option<OrderedQueuedTask>> {
+        // one transaction like the redis script, a task is always in one of the queues and pops
+        // are serialized on the `pop` counter's row so only one of them sees the free slot
+        let mut tx = self.pool.begin().await?;
+        next_counter_values(&mut *tx, "pop", 1).await?;
+
+        let parallel_task_count = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM temp_queue")
+            .fetch_one(&mut *tx)
+            .await? as usize;
+
+        if parallel_task_count >= max_threads {
+            return Ok(None);
+        }
+
+        let lock = match self.postgres {
+            true => " FOR UPDATE SKIP LOCKED",
+            false => "",
+        };
+        let Some((queued_task, score)) = sqlx::query_as::<_, (String, i64)>(&format!(
+            "SELECT queued_task, score FROM queue ORDER BY score, queued_task LIMIT 1{lock}"
+        ))
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Ok(None);
+        };
+
+        sqlx::query("DELETE FROM queue WHERE queued_task = $1")
+            .bind(&queued_task)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("INSERT INTO temp_queue (queued_task) VALUES ($1) ON CONFLICT DO NOTHING")
+            .bind(&queued_task)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(Some(OrderedQueuedTask {
+            score: score as usize,
+            queued_task: serde_json::from_str(&queued_task).unwrap(),
+        }))
+    }
+
+    async fn get_temp_queue(&self) -> StorageResult<Vec<QueuedTask>> {
+        Ok(
+            sqlx::query_scalar::<_, String>("SELECT queued_task FROM temp_queue")
+                .fetch_all(&self.pool)
+                .await?
+                .iter()
+                .map(|queued_task| serde_json::from_str(queued_task).unwrap())
+                .collect(),
+        )
+    }
+
+    async fn remove_from_temp_queue(&self, queued_task: &QueuedTask) -> StorageResult<()> {
+        sqlx::query("DELETE FROM temp_queue WHERE queued_task = $1")
+            .bind(serde_json::to_string(queued_task).unwrap())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_next_backfill_id(&self) -> StorageResult<usize> {
+        self.next_value("backfill").await
+    }
+
+    async fn set_backfill(&self, backfill: &Backfill) -> StorageResult<()> {
+        sqlx::query(
+            "INSERT INTO backfills (backfill_id, dag_name, backfill) VALUES ($1, $2, $3)
+            ON CONFLICT (backfill_id) DO UPDATE SET backfill = excluded.backfill",
+        )
+        .bind(backfill.backfill_id as i64)
+        .bind(&backfill.dag_name)
+        .bind(serde_json::to_string(backfill).unwrap())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_backfill(&self, backfill_id: usize) -> StorageResult<Option<Backfill>> {
+        Ok(
+            sqlx::query_scalar::<_, String>(
+                "SELECT backfill FROM backfills WHERE backfill_id = $1",
+            )
+            .bind(backfill_id as i64)
+            .fetch_optional(&self.pool)
+            .await?
+            .map(|backfill| serde_json::from_str(&backfill).unwrap()),
+        )
+    }
+
+    async fn get_backfills(&self, dag_name: &str) -> StorageResult<Vec<Backfill>> {
+        Ok(sqlx::query_scalar::<_, String>(
+            "SELECT backfill FROM backfills WHERE dag_name = $1 ORDER BY backfill_id",
+        )
+        .bind(dag_name)
+        .fetch_all(&self.pool)
+        .await?
+        .iter()
+        .map(|backfill| serde_json::from_str(backfill).unwrap())
+        .collect())
+    }
+
+    async fn publish_event(&self, event: &Event) -> StorageResult<()> {
+        // numbered in the transaction so the counter's row lock makes events commit in order
+        let mut tx = self.pool.begin().await?;
+        let seq = next_counter_values(&mut *tx, "event", 1).await?.start;
+        sqlx::query("INSERT INTO events (seq, event) VALUES ($1, $2)")
+            .bind(seq as i64)
+            .bind(serde_json::to_string(event).unwrap())
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM events WHERE seq <= $1")
+            .bind(seq.saturating_sub(EVENTS_KEPT) as i64)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn subscribe_events(&self) -> StorageResult<EventStream> {
+        let last_seq = sqlx::query_scalar::<_, i64>("SELECT COALESCE(MAX(seq), 0) FROM events")
+            .fetch_one(&self.pool)
+            .await?;
+        let seen: BTreeSet<i64> =
+            sqlx::query_scalar::<_, i64>("SELECT seq FROM events WHERE seq > $1")
+                .bind(last_seq - EVENTS_REREAD)
+                .fetch_all(&self.pool)
+                .await?
+                .into_iter()
+                .collect();
+
+        // other processes can't notify this one, so the table is polled for new events, the
+        // last few seqs are read again in case one of them committed after a later one
+        let events = stream::unfold(
+            (self.pool.clone(), last_seq, seen),
+            |(pool, mut last_seq, mut seen)| async move {
+                loop {
+                    match sqlx::query_as::<_, (i64, String)>(
+                        "SELECT seq, event FROM events WHERE seq > $1 ORDER BY seq",
+                    )
+                    .bind(last_seq - EVENTS_REREAD)
+                    .fetch_all(&pool)
+                    .await
+                    {
+                        Ok(rows) => {
+                            let events: Vec<Event> = rows
+                                .iter()
+                                .filter(|(seq, _)| seen.insert(*seq))
+                                .map(|(_, event)| serde_json::from_str(event).unwrap())
+                                .collect();
+                            if let Some((seq, _)) = rows.last() {
+                                last_seq = last_seq.max(*seq);
+                            }
+                            seen = seen.split_off(&(last_seq - EVENTS_REREAD));
+                            if !events.is_empty() {
+                                return Some((events, (pool, last_seq, seen)));
+                            }
+                        }
+                        Err(err) => println!("failed to poll events: {err}"),
+                    }
+                    // TODO read from env
+                    tokio::time::sleep(Duration::from_millis(500)).await;
+                }
+            },
+        );
+        Ok(events.flat_map(stream::iter).boxed())
+    }
+}
@@ -17,6 +17,7 @@ static TASKS: OnceLock<Arc<Mutex<HashMap<String, Vec<Task>>>>> = OnceLock::new()
 static HASHES: OnceLock<Arc<Mutex<HashMap<String, String>>>> = OnceLock::new();
 static EDGES: OnceLock<Arc<Mutex<HashMap<String, HashSet<(usize, usize)>>>>> = OnceLock::new();
 static DAG_OPTIONS: OnceLock<Arc<Mutex<HashMap<String, DagOptions>>>> = OnceLock::new();
+static SCHEDULING_ERRORS: OnceLock<Arc<Mutex<HashMap<String, String>>>> = OnceLock::new();
 
 #[timed(duration(printer = "debug!"))]
 pub fn _get_default_tasks(dag_name: &str) -> Vec<Task> {
@@ -122,3 +123,22 @@ pub fn _set_options(dag_name: &str, options: DagOptions) {
 
     dag_options.remove(dag_name);
 }
+
+pub fn _get_scheduling_error(dag_name: &str) -> Option<String> {
+    SCHEDULING_ERRORS
+        .get_or_init(|| Arc::new(Mutex::new(HashMap::new())))
+        .lock()
+        .get(dag_name)
+        .cloned()
+}
+
+pub fn _set_scheduling_error(dag_name: &str, error: Option<String>) {
+    let mut scheduling_errors = SCHEDULING_ERRORS
+        .get_or_init(|| Arc::new(Mutex::new(HashMap::new())))
+        .lock();
+
+    match error {
+        Some(error) => scheduling_errors.insert(dag_name.to_owned(), error),
+        None => scheduling_errors.remove(dag_name),
+    };
+}
@@ -0,0 +1,512 @@
+use std::{
+    collections::{HashMap, HashSet},
+    env, fmt,
+    ops::Range,
+    str::FromStr,
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use deadpool_redis::redis::RedisError;
+use thepipelinetool::server::*;
+
+use crate::{
+    backfill::Backfill,
+    events::{Event, EventStream},
+    get_redis_key_prefix,
+    redis_pool::get_redis_pool,
+    redis_storage::RedisStorage,
+    sql_storage::SqlStorage,
+};
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Run {
+    pub run_id: usize,
+    pub date: DateTime<Utc>,
+
+    #[serde(default)]
+    pub data_interval_start: Option<DateTime<Utc>>,
+
+    #[serde(default)]
+    pub data_interval_end: Option<DateTime<Utc>>,
+}
+
+impl Run {
+    // substitutes `{{logical_date}}`, `{{data_interval_start}}` and `{{data_interval_end}}`
+    // in every string of the template args
+    pub fn render_template_args(&self, template_args: &Value) -> Value {
+        match template_args {
+            Value::String(s) => {
+                let mut s = s.replace("{{logical_date}}", &self.date.to_rfc3339());
+                if let Some(data_interval_start) = self.data_interval_start {
+                    s = s.replace("{{data_interval_start}}", &data_interval_start.to_rfc3339());
+                }
+                if let Some(data_interval_end) = self.data_interval_end {
+                    s = s.replace("{{data_interval_end}}", &data_interval_end.to_rfc3339());
+                }
+                Value::String(s)
+            }
+            Value::Array(values) => Value::Array(
+                values
+                    .iter()
+                    .map(|v| self.render_template_args(v))
+                    .collect(),
+            ),
+            Value::Object(map) => Value::Object(
+                map.iter()
+                    .map(|(k, v)| (k.clone(), self.render_template_args(v)))
+                    .collect(),
+            ),
+            _ => template_args.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RunStatus {
+    Running,
+    Success,
+    Failed,
+}
+
+impl RunStatus {
+    pub const ALL: [RunStatus; 3] = [RunStatus::Running, RunStatus::Success, RunStatus::Failed];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RunStatus::Running => "running",
+            RunStatus::Success => "success",
+            RunStatus::Failed => "failed",
+        }
+    }
+
+    // a run is running while any of its tasks is unfinished, then failed if any task failed
+    pub fn from_counts(unfinished: usize, failed: usize) -> Self {
+        if unfinished > 0 {
+            RunStatus::Running
+        } else if failed > 0 {
+            RunStatus::Failed
+        } else {
+            RunStatus::Success
+        }
+    }
+}
+
+impl FromStr for RunStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        RunStatus::ALL
+            .into_iter()
+            .find(|status| status.as_str() == s)
+            .ok_or_else(|| format!("unknown run status: {s}"))
+    }
+}
+
+// task statuses that can still change
+pub fn is_unfinished(status: &TaskStatus) -> bool {
+    matches!(
+        status,
+        TaskStatus::Pending | TaskStatus::Running | TaskStatus::Retrying
+    )
+}
+
+pub fn unfinished_statuses() -> [&'static str; 3] {
+    [
+        TaskStatus::Pending.as_str(),
+        TaskStatus::Running.as_str(),
+        TaskStatus::Retrying.as_str(),
+    ]
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+// filters and position of a page of run listings, runs are ordered by run id, or by logical
+// date and then run id within a date range
+#[derive(Deserialize, Default, Clone)]
+pub struct RunQuery {
+    #[serde(default)]
+    pub status: Option<RunStatus>,
+
+    // inclusive bounds on the logical date
+    #[serde(default)]
+    pub from: Option<DateTime<Utc>>,
+
+    #[serde(default)]
+    pub to: Option<DateTime<Utc>>,
+
+    // run id of the last run of the previous page
+    #[serde(default)]
+    pub cursor: Option<usize>,
+
+    // logical date of the cursor's run, which positions the pages of a date range, looked up
+    // by the server as the run may have been purged since
+    #[serde(skip)]
+    pub cursor_date: Option<DateTime<Utc>>,
+
+    #[serde(default)]
+    pub limit: Option<usize>,
+
+    #[serde(default)]
+    pub order: SortOrder,
+}
+
+// how many stderr lines of a failed attempt its result event carries, out of how many of the
+// last lines of its log
+pub const STDERR_TAIL_LINES: usize = 10;
+pub const STDERR_TAIL_SCAN: usize = 1000;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum LogStream {
+    Stdout,
+    Stderr,
+}
+
+impl FromStr for LogStream {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stdout" => Ok(LogStream::Stdout),
+            "stderr" => Ok(LogStream::Stderr),
+            _ => Err(format!("unknown log stream: {s}")),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LogLine {
+    pub stream: LogStream,
+
+    // `None` for lines written before timestamps were recorded
+    #[serde(default)]
+    pub timestamp: Option<DateTime<Utc>>,
+
+    pub line: String,
+}
+
+impl LogLine {
+    pub fn new(stream: LogStream, line: String) -> Self {
+        Self {
+            stream,
+            timestamp: Some(Utc::now()),
+            line,
+        }
+    }
+
+    // logs are stored one json encoded line per entry, entries written before that are plain
+    // stdout lines
+    pub fn parse(entry: &str) -> Self {
+        serde_json::from_str(entry).unwrap_or_else(|_| Self {
+            stream: LogStream::Stdout,
+            timestamp: None,
+            line: entry.to_string(),
+        })
+    }
+
+    pub fn to_entry(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+// what of an attempt was cut short by the size limits, see `limits`
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Truncated {
+    Log,
+    Result,
+}
+
+impl Truncated {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Truncated::Log => "log",
+            Truncated::Result => "result",
+        }
+    }
+}
+
+impl FromStr for Truncated {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "log" => Ok(Truncated::Log),
+            "result" => Ok(Truncated::Result),
+            _ => Err(format!("unknown truncated part: {s}")),
+        }
+    }
+}
+
+// the backend could not be reached or failed the request, missing entries are `None` instead
+#[derive(Debug)]
+pub struct StorageError(pub String);
+
+pub type StorageResult<T> = Result<T, StorageError>;
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<RedisError> for StorageError {
+    fn from(err: RedisError) -> Self {
+        StorageError(format!("redis: {err}"))
+    }
+}
+
+impl From<sqlx::Error> for StorageError {
+    fn from(err: sqlx::Error) -> Self {
+        StorageError(format!("database: {err}"))
+    }
+}
+
+// every piece of state the server and workers share, independent of where it is kept
+#[async_trait]
+pub trait Storage: Send + Sync {
+    // runs
+    async fn create_new_run(
+        &self,
+        dag_name: &str,
+        dag_hash: &str,
+        logical_date: DateTime<Utc>,
+        data_interval: (DateTime<Utc>, DateTime<Utc>),
+    ) -> StorageResult<usize>;
+    async fn get_run(&self, run_id: usize) -> StorageResult<Option<Run>>;
+    async fn get_runs(&self, dag_name: &str) -> StorageResult<Vec<Run>>;
+    async fn get_last_run(&self, dag_name: &str) -> StorageResult<Option<Run>>;
+    async fn get_recent_runs(&self, dag_name: &str) -> StorageResult<Vec<Run>>;
+    // at most `limit` runs matching the query
+    async fn list_runs(
+        &self,
+        dag_name: &str,
+        query: &RunQuery,
+        limit: usize,
+    ) -> StorageResult<Vec<(Run, RunStatus)>>;
+    async fn get_run_status(&self, run_id: usize) -> StorageResult<Option<RunStatus>>;
+    async fn is_run_of_dag(&self, dag_name: &str, run_id: usize) -> StorageResult<bool>;
+    // ids of the runs with a logical date before `older_than` or that aren't among the newest
+    // `keep` runs, oldest first
+    async fn get_expired_run_ids(
+        &self,
+        dag_name: &str,
+        older_than: Option<DateTime<Utc>>,
+        keep: Option<usize>,
+    ) -> StorageResult<Vec<usize>>;
+    async fn contains_logical_date(
+        &self,
+        dag_name: &str,
+        dag_hash: &str,
+        logical_date: DateTime<Utc>,
+    ) -> StorageResult<bool>;
+    // removes everything stored for the run except its logical date, which is kept so the
+    // scheduler doesn't trigger the same slot again
+    async fn delete_run(&self, dag_name: &str, run_id: usize) -> StorageResult<()>;
+
+    // tasks
+    async fn get_next_task_ids(&self, run_id: usize, count: usize) -> StorageResult<Range<usize>>;
+    // inserts the tasks with a pending status in a single atomic write
+    async fn insert_tasks(&self, run_id: usize, tasks: &[Task]) -> StorageResult<()>;
+    async fn get_all_tasks(&self, run_id: usize) -> StorageResult<Vec<Task>>;
+    async fn get_task_by_id(&self, run_id: usize, task_id: usize) -> StorageResult<Option<Task>>;
+    async fn set_template_args(
+        &self,
+        run_id: usize,
+        task_id: usize,
+        template_args: &Value,
+    ) -> StorageResult<()>;
+    async fn get_task_depth(&self, run_id: usize, task_id: usize) -> StorageResult<Option<usize>>;
+    async fn get_task_depths(&self, run_id: usize) -> StorageResult<HashMap<usize, usize>>;
+    async fn set_task_depths(
+        &self,
+        run_id: usize,
+        depths: &HashMap<usize, usize>,
+    ) -> StorageResult<()>;
+    async fn delete_task_depth(&self, run_id: usize, task_id: usize) -> StorageResult<()>;
+
+    // statuses, setting one also updates the run's status
+    async fn get_task_status(
+        &self,
+        run_id: usize,
+        task_id: usize,
+    ) -> StorageResult<Option<TaskStatus>>;
+    async fn set_task_status(
+        &self,
+        run_id: usize,
+        task_id: usize,
+        task_status: TaskStatus,
+    ) -> StorageResult<()>;
+    async fn increment_attempt(&self, run_id: usize, task_id: usize) -> StorageResult<usize>;
+    async fn get_attempt(&self, run_id: usize, task_id: usize) -> StorageResult<usize>;
+
+    // results
+    async fn insert_task_results(&self, run_id: usize, result: &TaskResult) -> StorageResult<()>;
+    async fn get_task_result(
+        &self,
+        run_id: usize,
+        task_id: usize,
+    ) -> StorageResult<Option<TaskResult>>;
+    async fn get_all_results(
+        &self,
+        run_id: usize,
+        task_id: usize,
+    ) -> StorageResult<Vec<TaskResult>>;
+    async fn get_dependency_keys(
+        &self,
+        run_id: usize,
+        task_id: usize,
+    ) -> StorageResult<HashMap<(usize, String), String>>;
+    async fn set_dependency_keys(
+        &self,
+        run_id: usize,
+        task_id: usize,
+        upstream: (usize, String),
+        v: String,
+    ) -> StorageResult<()>;
+
+    // logs
+    async fn get_log(
+        &self,
+        run_id: usize,
+        task_id: usize,
+        attempt: usize,
+    ) -> StorageResult<String> {
+        let count = self.get_log_line_count(run_id, task_id, attempt).await?;
+        Ok(self
+            .get_log_lines(run_id, task_id, attempt, 0, count)
+            .await?
+            .into_iter()
+            .map(|line| line.line)
+            .collect::<Vec<_>>()
+            .join("\n"))
+    }
+    // the last `count` stderr lines among the last `scan` lines of the log
+    async fn get_stderr_tail(
+        &self,
+        run_id: usize,
+        task_id: usize,
+        attempt: usize,
+        count: usize,
+        scan: usize,
+    ) -> StorageResult<Vec<String>> {
+        let total = self.get_log_line_count(run_id, task_id, attempt).await?;
+        let mut tail: Vec<String> = self
+            .get_log_lines(run_id, task_id, attempt, total.saturating_sub(scan), scan)
+            .await?
+            .into_iter()
+            .filter(|line| line.stream == LogStream::Stderr)
+            .map(|line| line.line)
+            .collect();
+        Ok(tail.split_off(tail.len().saturating_sub(count)))
+    }
+    async fn get_log_line_count(
+        &self,
+        run_id: usize,
+        task_id: usize,
+        attempt: usize,
+    ) -> StorageResult<usize>;
+    // at most `limit` lines starting at line `offset`
+    async fn get_log_lines(
+        &self,
+        run_id: usize,
+        task_id: usize,
+        attempt: usize,
+        offset: usize,
+        limit: usize,
+    ) -> StorageResult<Vec<LogLine>>;
+    // stdout lines also become the attempt's last output, kept apart from the log
+    async fn append_log(
+        &self,
+        run_id: usize,
+        task_id: usize,
+        attempt: usize,
+        line: &LogLine,
+    ) -> StorageResult<()>;
+    // the last stdout line of the attempt, which the runner reads the task's result from
+    async fn get_last_output(
+        &self,
+        run_id: usize,
+        task_id: usize,
+        attempt: usize,
+    ) -> StorageResult<Option<String>>;
+    // replaces the last output without appending to the log, for lines past the log size limit
+    async fn set_last_output(
+        &self,
+        run_id: usize,
+        task_id: usize,
+        attempt: usize,
+        line: &str,
+    ) -> StorageResult<()>;
+    async fn set_truncated(
+        &self,
+        run_id: usize,
+        task_id: usize,
+        attempt: usize,
+        truncated: Truncated,
+    ) -> StorageResult<()>;
+    async fn get_truncated(
+        &self,
+        run_id: usize,
+        task_id: usize,
+        attempt: usize,
+    ) -> StorageResult<Vec<Truncated>>;
+
+    // edges
+    async fn get_edges(&self, run_id: usize) -> StorageResult<HashSet<(usize, usize)>>;
+    async fn get_downstream(&self, run_id: usize, task_id: usize) -> StorageResult<Vec<usize>>;
+    async fn get_upstream(&self, run_id: usize, task_id: usize) -> StorageResult<Vec<usize>>;
+    async fn insert_edge(&self, run_id: usize, edge: (usize, usize)) -> StorageResult<()>;
+    async fn remove_edge(&self, run_id: usize, edge: (usize, usize)) -> StorageResult<()>;
+
+    // queue
+    async fn enqueue_task(&self, queued_task: &QueuedTask, depth: usize) -> StorageResult<()>;
+    async fn pop_priority_queue(
+        &self,
+        max_threads: usize,
+    ) -> StorageResult<Option<OrderedQueuedTask>>;
+    async fn get_temp_queue(&self) -> StorageResult<Vec<QueuedTask>>;
+    async fn remove_from_temp_queue(&self, queued_task: &QueuedTask) -> StorageResult<()>;
+
+    // backfills
+    async fn get_next_backfill_id(&self) -> StorageResult<usize>;
+    async fn set_backfill(&self, backfill: &Backfill) -> StorageResult<()>;
+    async fn get_backfill(&self, backfill_id: usize) -> StorageResult<Option<Backfill>>;
+    async fn get_backfills(&self, dag_name: &str) -> StorageResult<Vec<Backfill>>;
+
+    // events, creating a run, setting a task status and inserting a result each publish one
+    async fn publish_event(&self, event: &Event) -> StorageResult<()>;
+    // the events published from now on by any process sharing the storage
+    async fn subscribe_events(&self) -> StorageResult<EventStream>;
+}
+
+fn get_storage_backend() -> String {
+    env::var("STORAGE_BACKEND")
+        .unwrap_or("redis".to_string())
+        .to_string()
+}
+
+fn get_database_url() -> String {
+    env::var("DATABASE_URL")
+        .unwrap_or("sqlite://thepipelinetool.db?mode=rwc".to_string())
+        .to_string()
+}
+
+// `STORAGE_BACKEND=redis` (default) uses `REDIS_URL` and `REDIS_KEY_PREFIX`,
+// `STORAGE_BACKEND=sql` uses `DATABASE_URL` (sqlite:// or postgres://)
+pub async fn get_storage() -> Arc<dyn Storage> {
+    match get_storage_backend().as_str() {
+        "redis" => Arc::new(RedisStorage::new(get_redis_pool(), get_redis_key_prefix())),
+        "sql" => Arc::new(SqlStorage::connect(&get_database_url()).await),
+        backend => panic!("unknown STORAGE_BACKEND: {backend}"),
+    }
+}